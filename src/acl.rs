@@ -0,0 +1,242 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::file_watcher::FileWatcher;
+use crate::message_handler::{MessageType, Outgoing};
+use crate::paths;
+use crate::symlink;
+
+// What a client may do, set per token in the server's config. By default, everything.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub(crate) struct Access {
+    // Changes from the client are refused, it only gets ours
+    #[serde(default)]
+    pub(crate) read_only: bool,
+
+    // The files and directories the client can see, like `docs` or `docs/manual.pdf`.
+    // Anything outside of them doesn't exist as far as the client is concerned.
+    #[serde(default)]
+    pub(crate) paths: Option<Vec<String>>,
+}
+
+impl Access {
+    pub(crate) fn can_see(&self, path: &str) -> bool {
+        let Some(roots) = &self.paths else {
+            return true;
+        };
+        let Ok(path) = paths::normalize(path) else {
+            return false;
+        };
+        roots
+            .iter()
+            .filter_map(|root| paths::normalize(root).ok())
+            .any(|root| path == root || path.starts_with(&format!("{}/", root)))
+    }
+
+    // Like `can_see`, but also for where `path` really is on our side once symlinks are followed,
+    // so a link inside of a visible path can't lead to anything outside of them.
+    fn can_reach(&self, root: &Path, path: &str, follow: bool) -> bool {
+        if self.paths.is_none() {
+            return true;
+        }
+        let Ok(path) = paths::normalize(path) else {
+            return false;
+        };
+        self.can_see(&path) && paths::resolve(root, &path, follow).is_some_and(|resolved| self.can_see(&resolved))
+    }
+
+    // Whether a link at `path` pointing to `target` leads somewhere the client can see.
+    fn can_link(&self, root: &Path, path: &str, target: &str) -> bool {
+        self.paths.is_none() || symlink::target_path(path, target).is_some_and(|target| self.can_reach(root, &target, true))
+    }
+
+    // The visible paths inside of `path`, for changes to a directory the client can't see
+    // itself, but can see some of the contents of.
+    fn roots_under(&self, path: &str) -> Vec<String> {
+        let prefix = format!("{}/", path);
+        self.paths
+            .iter()
+            .flatten()
+            .filter_map(|root| paths::normalize(root).ok())
+            .filter(|root| root.starts_with(&prefix))
+            .collect()
+    }
+
+    // Whether the client may send us this message at all. `root` is the root of our tree.
+    pub(crate) fn check(&self, msg: &MessageType, root: &Path) -> Result<(), String> {
+        if self.read_only && msg.is_change() {
+            return Err("This client is read-only".to_string());
+        }
+
+        if let Some((path, _)) = paths::paths(msg).into_iter().find(|(path, follow)| !self.can_reach(root, path, *follow)) {
+            return Err(format!("'{}' is not visible to this client", path));
+        }
+
+        let links: Vec<(&String, &String)> = match msg {
+            MessageType::SymlinkEvent { path, target } => vec![(path, target)],
+            MessageType::Manifest { symlinks, .. } => symlinks.iter().collect(),
+            _ => vec![],
+        };
+        match links.into_iter().find(|(path, target)| !self.can_link(root, path, target)) {
+            Some((path, target)) => Err(format!("'{}' points to '{}', which is not visible to this client", path, target)),
+            None => Ok(()),
+        }
+    }
+
+    // Narrows something we're about to send down to what the client can see. A move can take
+    // things in or out of view, which the client sees as them being created or deleted.
+    pub(crate) fn filter(&self, outgoing: Outgoing, file_watcher: &Mutex<FileWatcher>) -> Vec<Outgoing> {
+        if self.paths.is_none() {
+            return vec![outgoing];
+        }
+
+        // What the client sees is decided by where things really are, a link can't show it more
+        let root = PathBuf::from(&file_watcher.lock().unwrap().root);
+        let root = root.as_path();
+        let msg = match outgoing {
+            Outgoing::File { ref path, .. } | Outgoing::Delta { ref path, .. } => return if self.can_reach(root, path, true) { vec![outgoing] } else { vec![] },
+            Outgoing::Files { mut files, mut metadata } => {
+                files.retain(|(path, _)| self.can_reach(root, path, true));
+                metadata.retain(|path, _| self.can_reach(root, path, true));
+                if files.is_empty() {
+                    return vec![];
+                }
+                return vec![Outgoing::Files { files, metadata }];
+            }
+            Outgoing::Message(msg) => msg,
+        };
+
+        match msg {
+            MessageType::Manifest { mut entries, mut dirs, mut symlinks } => {
+                entries.retain(|entry| self.can_reach(root, &entry.path, true));
+                dirs.retain(|dir| self.can_reach(root, dir, true));
+                symlinks.retain(|path, target| self.can_reach(root, path, false) && self.can_link(root, path, target));
+                vec![Outgoing::Message(MessageType::Manifest { entries, dirs, symlinks })]
+            }
+            MessageType::DeleteEvent { ref path, .. } | MessageType::DeleteDir { ref path, .. } if !self.can_reach(root, path, false) => {
+                self.roots_under(path)
+                    .into_iter()
+                    .map(|path| Outgoing::Message(MessageType::DeleteDir { path, parent_hashes: None }))
+                    .collect()
+            }
+            MessageType::MoveEvent { ref old_path, ref new_path, .. } => {
+                let (old_visible, new_visible) = (self.can_reach(root, old_path, false), self.can_reach(root, new_path, false));
+                if old_visible && new_visible {
+                    return vec![Outgoing::Message(msg)];
+                }
+
+                let mut outgoing = Vec::new();
+                if old_visible {
+                    let file_watcher = file_watcher.lock().unwrap();
+                    let path = old_path.clone();
+                    outgoing.push(Outgoing::Message(if file_watcher.is_dir(new_path) {
+                        MessageType::DeleteDir { path, parent_hashes: None }
+                    } else {
+                        MessageType::DeleteEvent { path, parent_hash: None }
+                    }));
+                } else {
+                    for path in self.roots_under(old_path) {
+                        outgoing.push(Outgoing::Message(MessageType::DeleteDir { path, parent_hashes: None }));
+                    }
+                }
+
+                let file_watcher = file_watcher.lock().unwrap();
+                if new_visible {
+                    outgoing.extend(file_watcher.make_tree_outgoing(new_path));
+                } else {
+                    for path in self.roots_under(new_path) {
+                        outgoing.extend(file_watcher.make_tree_outgoing(&path));
+                    }
+                }
+                outgoing
+            }
+            MessageType::SymlinkEvent { ref path, ref target } if !self.can_link(root, path, target) => vec![],
+            msg => {
+                if paths::paths(&msg).iter().all(|(path, follow)| self.can_reach(root, path, *follow)) {
+                    vec![Outgoing::Message(msg)]
+                } else {
+                    vec![]
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SyncOptions;
+
+    fn access(paths: &[&str]) -> Access {
+        Access { read_only: false, paths: Some(paths.iter().map(|path| path.to_string()).collect()) }
+    }
+
+    fn deleted(outgoing: &[Outgoing]) -> Vec<String> {
+        outgoing
+            .iter()
+            .map(|outgoing| match outgoing {
+                Outgoing::Message(MessageType::DeleteEvent { path, .. }) => format!("file {}", path),
+                Outgoing::Message(MessageType::DeleteDir { path, .. }) => format!("dir {}", path),
+                _ => panic!("expected a delete"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sees_everything_without_paths() {
+        assert!(Access::default().can_see("anything/at/all"));
+    }
+
+    #[test]
+    fn sees_inside_of_its_paths_only() {
+        let access = access(&["foo", "docs/manual.pdf"]);
+        assert!(access.can_see("foo"));
+        assert!(access.can_see("foo/bar"));
+        assert!(access.can_see("./foo/bar"));
+        assert!(access.can_see("docs/manual.pdf"));
+        assert!(!access.can_see("foobar"));
+        assert!(!access.can_see("foobar/baz"));
+        assert!(!access.can_see("docs"));
+        assert!(!access.can_see("docs/manual.pdf.bak"));
+        assert!(!access.can_see("foo/../secret"));
+    }
+
+    #[test]
+    fn ignores_paths_that_cannot_be_normalized() {
+        let access = access(&["../outside", "/etc", "foo/../bar", "docs"]);
+        assert!(access.can_see("docs/a"));
+        assert!(!access.can_see("bar"));
+        assert!(!access.can_see("etc"));
+        assert!(!access.can_see("outside"));
+        assert_eq!(access.roots_under("foo"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn finds_the_paths_under_a_directory() {
+        let access = access(&["docs/a", "./docs/b/c", "docsx/d", "docs", "other"]);
+        assert_eq!(access.roots_under("docs"), vec!["docs/a", "docs/b/c"]);
+        assert_eq!(access.roots_under("doc"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn turns_a_hidden_delete_into_deletes_of_what_is_visible() {
+        let root = std::env::temp_dir().join(format!("remote-fs-acl-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        let file_watcher = Mutex::new(FileWatcher::new(&root.to_string_lossy(), SyncOptions::default()).unwrap());
+        let access = access(&["docs/a", "docs/b", "docsx"]);
+
+        let delete_file = |path: &str| Outgoing::Message(MessageType::DeleteEvent { path: path.to_string(), parent_hash: None });
+        let delete_dir = |path: &str| Outgoing::Message(MessageType::DeleteDir { path: path.to_string(), parent_hashes: None });
+        assert_eq!(deleted(&access.filter(delete_file("docs"), &file_watcher)), vec!["dir docs/a", "dir docs/b"]);
+        assert_eq!(deleted(&access.filter(delete_dir("docs"), &file_watcher)), vec!["dir docs/a", "dir docs/b"]);
+        assert_eq!(deleted(&access.filter(delete_file("docs/a"), &file_watcher)), vec!["file docs/a"]);
+        assert_eq!(deleted(&access.filter(delete_file("docsx/e"), &file_watcher)), vec!["file docsx/e"]);
+        assert!(access.filter(delete_file("doc"), &file_watcher).is_empty());
+        assert!(access.filter(delete_dir("secret"), &file_watcher).is_empty());
+
+        drop(file_watcher);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,127 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::time::SystemTime;
+use tokio_rustls::rustls::crypto::ring;
+
+use crate::acl::Access;
+use crate::config::Config;
+
+// A client that's allowed to connect. The name is what it shows up as in the logs.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct ClientToken {
+    pub(crate) name: String,
+    pub(crate) token: String,
+
+    #[serde(flatten)]
+    pub(crate) access: Access,
+}
+
+// Who a client authenticated as. Without tokens on the server, it's nobody in particular,
+// with access to everything.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Identity {
+    pub(crate) name: Option<String>,
+    pub(crate) token: Option<String>,
+    pub(crate) access: Access,
+}
+
+impl Identity {
+    pub(crate) fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or("anonymous")
+    }
+}
+
+// The tokens the server accepts. They're read from the server's config file again whenever it
+// changes, so tokens can be added or revoked without a restart.
+// Without a list of tokens anyone can connect, with an empty one nobody can.
+pub(crate) struct Auth {
+    config_path: Option<String>,
+    modified: Option<SystemTime>,
+    tokens: Option<Vec<ClientToken>>,
+
+    // Makes every client read-only, see `ServerConfig::read_only`
+    read_only: bool,
+}
+
+impl Auth {
+    pub(crate) fn new(config_path: Option<&str>, tokens: Option<Vec<ClientToken>>, read_only: bool) -> Self {
+        let modified = config_path.and_then(modified);
+        Auth { config_path: config_path.map(str::to_string), modified, tokens, read_only }
+    }
+
+    pub(crate) fn is_open(&self) -> bool {
+        self.tokens.is_none()
+    }
+
+    // Picks up the tokens (and whether clients are read-only) from the config file if it changed
+    // since we last read it. Returns whether they were reloaded. A config we can't read leaves the current tokens in place.
+    pub(crate) fn reload(&mut self) -> bool {
+        let Some(path) = &self.config_path else {
+            return false;
+        };
+
+        let modified = modified(path);
+        if modified == self.modified {
+            return false;
+        }
+        self.modified = modified;
+
+        match Config::from_file(path) {
+            Ok(Config::Server(config)) => {
+                match &config.tokens {
+                    Some(tokens) => eprintln!("Reloaded {} tokens from '{}'", tokens.len(), path),
+                    None => eprintln!("No tokens in '{}' anymore, anyone can connect", path),
+                }
+                if config.read_only != self.read_only {
+                    eprintln!("Clients are {} now", if config.read_only { "read-only" } else { "allowed to make changes" });
+                }
+                self.tokens = config.tokens;
+                self.read_only = config.read_only;
+                true
+            }
+            Ok(Config::Client(_)) => {
+                eprintln!("'{}' is no longer a server config, keeping the tokens we had", path);
+                false
+            }
+            Err(e) => {
+                eprintln!("Failed to reload tokens from '{}', keeping the ones we had: {}", path, e);
+                false
+            }
+        }
+    }
+
+    pub(crate) fn authenticate(&self, token: Option<&str>) -> Result<Identity, String> {
+        let mut identity = self.identify(token)?;
+        identity.access.read_only |= self.read_only;
+        Ok(identity)
+    }
+
+    fn identify(&self, token: Option<&str>) -> Result<Identity, String> {
+        let Some(tokens) = &self.tokens else {
+            return Ok(Identity::default());
+        };
+        let token = token.ok_or("This server requires a token")?;
+
+        // Only the hashes are compared, so how long that takes says nothing about the token
+        let digest = Sha256::digest(token.as_bytes());
+        tokens
+            .iter()
+            .find(|t| Sha256::digest(t.token.as_bytes()) == digest)
+            .map(|t| Identity { name: Some(t.name.clone()), token: Some(token.to_string()), access: t.access.clone() })
+            .ok_or("Invalid token".to_string())
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// A random 256-bit token, as hex.
+pub(crate) fn generate_token() -> Result<String, String> {
+    let mut bytes = [0u8; 32];
+    ring::default_provider()
+        .secure_random
+        .fill(&mut bytes)
+        .map_err(|_| "Failed to generate a random token".to_string())?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
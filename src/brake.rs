@@ -0,0 +1,336 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::journal::STATE_DIR;
+
+// How often a running instance looks for a confirmation, see `confirm`
+const CONFIRM_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Written by `remote-fs confirm-deletes`, the running instance picks it up and removes it
+const CONFIRM_FILE: &str = "confirm-deletes";
+
+// What's waiting for a confirmation, so `remote-fs confirm-deletes` can show it
+const HELD_FILE: &str = "held-deletes";
+
+// Stops a storm of deletes, like an `rm -rf` in the wrong place, from reaching the other side.
+// Once more files than this are deleted within the window, deletes in either direction are
+// held until someone confirms them with `remote-fs confirm-deletes <path>`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct DeleteBrake {
+    #[serde(default)]
+    pub(crate) max_files: Option<usize>,
+
+    // Of the files we had before the window started
+    #[serde(default)]
+    pub(crate) max_percent: Option<f64>,
+
+    #[serde(default = "default_window_secs")]
+    pub(crate) window_secs: u64,
+}
+
+fn default_window_secs() -> u64 {
+    60
+}
+
+// A delete that's waiting for a confirmation. Incoming ones came from the other side and
+// haven't been applied here, outgoing ones were made here and haven't been sent.
+#[derive(Debug, Clone)]
+pub(crate) struct HeldDelete {
+    pub(crate) path: String,
+    pub(crate) dir: bool,
+    pub(crate) incoming: bool,
+}
+
+pub(crate) struct Brake {
+    config: Option<DeleteBrake>,
+    state_dir: PathBuf,
+
+    // When files were deleted, and how many
+    recent: VecDeque<(Instant, usize)>,
+
+    held: Vec<HeldDelete>,
+    held_changed: bool,
+
+    // Set when we refused to sync with an empty tree, see `refuse_empty`
+    refused: Option<String>,
+    allow_empty: bool,
+
+    last_check: Instant,
+}
+
+impl Brake {
+    pub(crate) fn new(root: &str, config: Option<DeleteBrake>) -> Self {
+        Brake {
+            config,
+            state_dir: Path::new(root).join(STATE_DIR),
+            recent: VecDeque::new(),
+            held: Vec::new(),
+            held_changed: false,
+            refused: None,
+            allow_empty: false,
+            last_check: Instant::now(),
+        }
+    }
+
+    // Records that `files` files are about to be deleted, out of the `indexed` we have left.
+    // Returns false if the delete has to be held instead.
+    pub(crate) fn allow(&mut self, files: usize, indexed: usize) -> bool {
+        let Some(config) = &self.config else {
+            return true;
+        };
+        if !self.held.is_empty() {
+            return false;
+        }
+
+        let window = Duration::from_secs(config.window_secs);
+        while self.recent.front().is_some_and(|(at, _)| at.elapsed() > window) {
+            self.recent.pop_front();
+        }
+
+        let deleted = self.recent.iter().map(|(_, files)| files).sum::<usize>() + files;
+        let before = indexed + deleted - files;
+        let too_many = config.max_files.is_some_and(|max| deleted > max);
+        let too_much = config.max_percent.is_some_and(|max| deleted as f64 > before as f64 * max / 100.0);
+        if too_many || too_much {
+            eprintln!(
+                "{} of {} files deleted within {}s, holding deletes until they're confirmed with `remote-fs confirm-deletes {}`",
+                deleted,
+                before,
+                config.window_secs,
+                self.root().display()
+            );
+            return false;
+        }
+
+        self.recent.push_back((Instant::now(), files));
+        true
+    }
+
+    pub(crate) fn is_holding(&self) -> bool {
+        !self.held.is_empty()
+    }
+
+    // Something that's already held, or inside of a directory that is, isn't held again. A
+    // directory takes the place of whatever was held inside of it.
+    pub(crate) fn hold(&mut self, delete: HeldDelete) {
+        if self.held.iter().any(|held| is_within(&delete.path, &held.path)) {
+            return;
+        }
+        eprintln!("Holding {} delete of {}", if delete.incoming { "incoming" } else { "outgoing" }, delete.path);
+        if delete.dir {
+            self.held.retain(|held| !is_within(&held.path, &delete.path));
+        }
+        self.held.push(delete);
+        self.held_changed = true;
+    }
+
+    // Something was changed again after its delete was held, so confirming it would lose that change.
+    // A change inside of a directory keeps the directory too.
+    pub(crate) fn cancel(&mut self, path: &str) {
+        let count = self.held.len();
+        self.held.retain(|held| !is_within(path, &held.path));
+        if self.held.len() != count {
+            eprintln!("{} changed after its delete was held, no longer deleting it", path);
+            self.held_changed = true;
+        }
+    }
+
+    // Whether to refuse syncing with the other side's empty tree. The first one after a
+    // confirmation goes through.
+    pub(crate) fn refuse_empty(&mut self, reason: String) -> bool {
+        if self.allow_empty {
+            self.allow_empty = false;
+            return false;
+        }
+
+        eprintln!("{}, refusing to sync until it's confirmed with `remote-fs confirm-deletes {}`", reason, self.root().display());
+        self.refused = Some(reason);
+        self.held_changed = true;
+        true
+    }
+
+    // Checks whether the held deletes were confirmed, and returns them if they were. Also
+    // keeps the list `remote-fs confirm-deletes` shows up to date.
+    pub(crate) fn confirmed(&mut self) -> Option<Vec<HeldDelete>> {
+        if self.last_check.elapsed() < CONFIRM_CHECK_INTERVAL {
+            return None;
+        }
+        self.last_check = Instant::now();
+
+        let confirm = self.state_dir.join(CONFIRM_FILE);
+        if confirm.exists() {
+            if let Err(e) = std::fs::remove_file(&confirm) {
+                eprintln!("Failed to remove {}: {:?}", confirm.display(), e);
+            }
+
+            eprintln!("Deletes confirmed, releasing {} held deletes", self.held.len());
+            let released = std::mem::take(&mut self.held);
+            self.recent.clear();
+            self.allow_empty = self.refused.take().is_some();
+            self.save_held();
+            return Some(released);
+        }
+
+        if self.held_changed {
+            self.save_held();
+        }
+        None
+    }
+
+    fn save_held(&mut self) {
+        self.held_changed = false;
+        let path = self.state_dir.join(HELD_FILE);
+        if self.held.is_empty() && self.refused.is_none() {
+            if path.exists()
+                && let Err(e) = std::fs::remove_file(&path)
+            {
+                eprintln!("Failed to remove {}: {:?}", path.display(), e);
+            }
+            return;
+        }
+
+        let mut lines: Vec<String> = self.refused.iter().cloned().collect();
+        lines.extend(self.held.iter().map(|held| {
+            let what = if held.dir { "directory" } else { "file" };
+            let from = if held.incoming { "from the other side" } else { "made here" };
+            format!("Delete {} {}, {}", what, held.path, from)
+        }));
+        let result = std::fs::create_dir_all(&self.state_dir).and_then(|_| std::fs::write(&path, lines.join("\n") + "\n"));
+        if let Err(e) = result {
+            eprintln!("Failed to write {}: {:?}", path.display(), e);
+        }
+    }
+
+    fn root(&self) -> &Path {
+        self.state_dir.parent().unwrap()
+    }
+}
+
+// Whether `path` is `dir` or something inside of it, `foo` doesn't contain `foobar`
+fn is_within(path: &str, dir: &str) -> bool {
+    path.strip_prefix(dir).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+// Tells the instance syncing `root` to go ahead with what it's holding. Returns what that is.
+pub(crate) fn confirm(root: &str) -> Result<String, String> {
+    let state_dir = Path::new(root).join(STATE_DIR);
+    let held = std::fs::read_to_string(state_dir.join(HELD_FILE))
+        .map_err(|_| format!("Nothing in '{}' is waiting for a confirmation", root))?;
+    std::fs::write(state_dir.join(CONFIRM_FILE), "")
+        .map_err(|e| format!("Failed to confirm deletes in '{}': {}", root, e))?;
+    Ok(held)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn brake(max_files: Option<usize>, max_percent: Option<f64>) -> Brake {
+        Brake::new("/nonexistent", Some(DeleteBrake { max_files, max_percent, window_secs: 60 }))
+    }
+
+    fn delete(path: &str, dir: bool) -> HeldDelete {
+        HeldDelete { path: path.to_string(), dir, incoming: false }
+    }
+
+    fn held(brake: &Brake) -> Vec<&str> {
+        brake.held.iter().map(|held| held.path.as_str()).collect()
+    }
+
+    // So the next `confirmed` looks for a confirmation straight away
+    fn check_now(brake: &mut Brake) {
+        brake.last_check = Instant::now().checked_sub(CONFIRM_CHECK_INTERVAL).unwrap();
+    }
+
+    #[test]
+    fn trips_once_too_many_files_are_deleted() {
+        let mut brake = brake(Some(3), None);
+        assert!(brake.allow(2, 100));
+        assert!(brake.allow(1, 98));
+        assert!(!brake.allow(1, 97));
+    }
+
+    #[test]
+    fn trips_once_too_much_of_the_tree_is_deleted() {
+        // 3 of 10 is fine, 6 of the same 10 isn't, even though only 7 are left to compare to
+        let mut brake = brake(None, Some(50.0));
+        assert!(brake.allow(3, 10));
+        assert!(!brake.allow(3, 7));
+    }
+
+    #[test]
+    fn forgets_deletes_outside_of_the_window() {
+        let mut brake = brake(Some(3), None);
+        let long_ago = Instant::now().checked_sub(Duration::from_secs(120)).unwrap();
+        brake.recent.push_back((long_ago, 3));
+        assert!(brake.allow(3, 100));
+        assert!(!brake.allow(1, 97));
+    }
+
+    #[test]
+    fn lets_everything_through_without_a_config() {
+        let mut brake = Brake::new("/nonexistent", None);
+        assert!(brake.allow(1000, 1000));
+    }
+
+    #[test]
+    fn holds_everything_once_something_is_held() {
+        let mut brake = brake(Some(100), None);
+        brake.hold(delete("a", false));
+        assert!(brake.is_holding());
+        assert!(!brake.allow(1, 100));
+    }
+
+    #[test]
+    fn holds_on_directory_boundaries() {
+        let mut brake = brake(Some(100), None);
+        brake.hold(delete("foo", true));
+        brake.hold(delete("foo/a", false));
+        brake.hold(delete("foobar", false));
+        assert_eq!(held(&brake), vec!["foo", "foobar"]);
+
+        // A directory takes the place of what's inside of it, but not of its neighbours
+        brake.hold(delete("bar/a", false));
+        brake.hold(delete("barbaz", false));
+        brake.hold(delete("bar", true));
+        assert_eq!(held(&brake), vec!["foo", "foobar", "barbaz", "bar"]);
+    }
+
+    #[test]
+    fn cancels_on_directory_boundaries() {
+        let mut brake = brake(Some(100), None);
+        brake.hold(delete("foo", true));
+        brake.hold(delete("foobar", false));
+
+        brake.cancel("foobar");
+        assert_eq!(held(&brake), vec!["foo"]);
+        brake.cancel("fo");
+        assert_eq!(held(&brake), vec!["foo"]);
+        brake.cancel("foo/a");
+        assert!(!brake.is_holding());
+    }
+
+    #[test]
+    fn refuses_an_empty_tree_until_confirmed() {
+        let root = std::env::temp_dir().join(format!("remote-fs-brake-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let root = root.to_string_lossy().to_string();
+        let mut brake = Brake::new(&root, None);
+
+        assert!(brake.refuse_empty("The other side is empty".to_string()));
+        check_now(&mut brake);
+        assert!(brake.confirmed().is_none());
+        assert!(confirm(&root).unwrap().contains("The other side is empty"));
+
+        check_now(&mut brake);
+        assert!(brake.confirmed().unwrap().is_empty());
+        assert!(confirm(&root).is_err());
+
+        // Only the first one after the confirmation goes through
+        assert!(!brake.refuse_empty("The other side is empty".to_string()));
+        assert!(brake.refuse_empty("The other side is empty".to_string()));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
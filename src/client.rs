@@ -1,11 +1,36 @@
-use tokio::net::TcpStream;
-use tokio::io::AsyncWriteExt;
-use crate::message_handler::read_msg;
+use tokio::net::{lookup_host, TcpStream};
+use tokio_rustls::TlsConnector;
+use tokio::sync::mpsc;
+use crate::message_handler::{read_msg, write_outgoing, MessageType, Outgoing};
+use crate::conflict::PeerHashes;
+use crate::config::{ClientConfig, LocalChanges};
+use crate::file_watcher::{fnv1a64, FileWatcher};
+use crate::protocol::{client_authenticate, client_handshake, Authority, Session};
+use crate::tls::{self, Reader, Writer};
+use crate::transfer::PeerTransfers;
 use std::fs::create_dir_all;
+use std::net::SocketAddr;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-pub(crate) async fn run(addr: &str, root: &str) {
+// How often we record what we're in sync with, see `journal.rs`
+const JOURNAL_INTERVAL: Duration = Duration::from_secs(1);
+
+// How long to wait for each of the server's addresses to answer
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long to wait before the first reconnect, doubled after every failed attempt
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+// Where the file watcher sends its changes, None while we're disconnected
+type Connection = Arc<Mutex<Option<mpsc::UnboundedSender<Outgoing>>>>;
+
+pub(crate) async fn run(config: &ClientConfig, connector: Option<TlsConnector>) {
+    let (host, port, root) = (config.host(), config.port, config.location.as_str());
+    let token = config.token.as_deref();
+    let local_changes = config.local_changes();
 
     // Ensure the folder exists
     if !Path::new(root).exists() {
@@ -15,57 +40,273 @@ pub(crate) async fn run(addr: &str, root: &str) {
         });
     }
 
-    let addr = format!("{}:5343", addr);
-    let stream = TcpStream::connect(&addr).await;
-    if stream.is_err() {
-        eprintln!("Failed to connect to {}: {:?}", addr, stream.err().unwrap());
+    // IPv6 addresses need brackets to tell them apart from the port
+    let addr = if host.contains(':') { format!("[{}]:{}", host, port) } else { format!("{}:{}", host, port) };
+    let file_watcher = FileWatcher::new(root, config.options.clone()).unwrap_or_else(|e| {
+        eprintln!("Failed to watch '{}': {}", root, e);
         std::process::exit(1);
-    }
-    let stream = stream.unwrap();
-    let file_watcher = Arc::new(Mutex::new(crate::FileWatcher::new(root).unwrap()));
-    let file_watcher2 = file_watcher.clone();
-    let (reader, mut writer) = stream.into_split();
+    });
+    let file_watcher = Arc::new(Mutex::new(file_watcher));
+    file_watcher.lock().unwrap().load_journal();
+    file_watcher.lock().unwrap().set_local_changes(local_changes);
+    file_watcher.lock().unwrap().set_extra_files(config.extra_files);
+    let connection: Connection = Arc::new(Mutex::new(None));
 
-    // Just keep waiting for file events, and pass those on to the other side
+    // Just keep waiting for file events, and pass those on to the other side. Changes made while
+    // we're disconnected aren't lost, the journal picks them up once we're back.
+    let watcher_file_watcher = file_watcher.clone();
+    let watcher_connection = connection.clone();
     tokio::spawn(async move {
-        eprintln!("File watcher started watching '{}'", file_watcher.lock().unwrap().root);
+        eprintln!("File watcher started watching '{}'", watcher_file_watcher.lock().unwrap().root);
         loop {
-            let event = file_watcher.lock().unwrap().try_get_event();
+            // Deletes the brake was holding on to, once they're confirmed
+            let released = watcher_file_watcher.lock().unwrap().take_released();
+            if let Some(tx) = &*watcher_connection.lock().unwrap() {
+                for outgoing in released {
+                    if tx.send(outgoing).is_err() {
+                        eprintln!("Failed to send released delete");
+                    }
+                }
+            }
+
+            let event = watcher_file_watcher.lock().unwrap().try_get_event();
             if event.is_err() {
                 tokio::time::sleep(std::time::Duration::from_millis(10)).await;
                 continue;
             }
 
+            // A read-only or pulling client never sends its own changes, at most it asks for the server's copy back
             let event = event.unwrap();
-            let event_data = file_watcher.lock().unwrap().make_msg_data(&event);
-            if event_data.is_none() {
+            let outgoing = match local_changes {
+                LocalChanges::Send => watcher_file_watcher.lock().unwrap().make_outgoing(&event),
+                LocalChanges::Keep => None,
+                LocalChanges::Revert => watcher_file_watcher.lock().unwrap().revert(&event),
+            };
+            if outgoing.is_none() {
                 // eprintln!("Failed to serialize {:?} event", event);
                 continue;
             }
 
-            let result = writer.write_all(&event_data.unwrap()).await;
-            if result.is_err() {
+            if let Some(tx) = &*watcher_connection.lock().unwrap()
+                && tx.send(outgoing.unwrap()).is_err()
+            {
                 eprintln!("Failed to send  {:?} event", event);
             }
         }
     });
 
-    // Server file update reader
-    let mut reader = reader;
+    let mut backoff = Backoff::new();
     loop {
+        match connect(host, port, &addr, connector.as_ref(), token, &file_watcher).await {
+            Ok((reader, writer, session)) => {
+                backoff.reset();
+                sync(&addr, reader, writer, session, &file_watcher, &connection).await;
+            }
+            Err(e) => eprintln!("{}", e),
+        }
+
+        let delay = backoff.next_delay();
+        eprintln!("Reconnecting to {} in {:.1}s", addr, delay.as_secs_f32());
+        tokio::time::sleep(delay).await;
+    }
+}
+
+async fn connect(
+    host: &str,
+    port: u16,
+    addr: &str,
+    connector: Option<&TlsConnector>,
+    token: Option<&str>,
+    file_watcher: &Mutex<FileWatcher>,
+) -> Result<(Reader, Writer, Session), String> {
+    // How we sync and what we offer, as agreed on with the server below
+    let (authority, direction, capabilities) = {
+        let file_watcher = file_watcher.lock().unwrap();
+        (file_watcher.authority(), file_watcher.direction(), file_watcher.capabilities())
+    };
+
+    // Resolved again for every attempt, the server may well have moved in the meantime
+    let addresses: Vec<SocketAddr> = lookup_host((host, port))
+        .await
+        .map_err(|e| format!("Failed to resolve '{}': {}", host, e))?
+        .collect();
+    if addresses.is_empty() {
+        return Err(format!("'{}' did not resolve to any addresses", host));
+    }
+
+    // Try every address in the order the resolver gave them to us
+    let mut stream = None;
+    let mut errors = Vec::new();
+    for address in &addresses {
+        match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(address)).await {
+            Ok(Ok(s)) => {
+                stream = Some(s);
+                break;
+            }
+            Ok(Err(e)) => errors.push(format!("{}: {}", address, e)),
+            Err(_) => errors.push(format!("{}: timed out", address)),
+        }
+    }
+    let stream = stream.ok_or_else(|| format!("Failed to connect to {} ({})", addr, errors.join(", ")))?;
+
+    let peer_addr = stream.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+    let (mut reader, mut writer) = tls::connect(stream, host, connector)
+        .await
+        .map_err(|e| format!("Failed to secure connection to {}: {}", addr, e))?;
+    let session = client_handshake(&mut reader, &mut writer, &capabilities)
+        .await
+        .map_err(|e| format!("Handshake with {} failed: {}", addr, e))?;
+    let name = client_authenticate(&mut reader, &mut writer, token, authority, direction)
+        .await
+        .map_err(|e| format!("Failed to authenticate with {}: {}", addr, e))?;
+
+    eprintln!(
+        "Connected to {} ({}) as {} using protocol v{} with capabilities {:?}",
+        addr,
+        peer_addr,
+        name.as_deref().unwrap_or("anonymous"),
+        session.version,
+        session.capabilities
+    );
+    Ok((reader, writer, session))
+}
+
+// Syncs over a single connection until the server goes away. The server starts by sending its
+// manifest, which we reconcile with the journal to catch up on whatever we missed.
+async fn sync(
+    addr: &str,
+    mut reader: Reader,
+    mut writer: Writer,
+    session: Session,
+    file_watcher: &Arc<Mutex<FileWatcher>>,
+    connection: &Connection,
+) {
+    let (tx, mut rx) = mpsc::unbounded_channel::<Outgoing>();
+    let peer = Arc::new(Mutex::new(PeerHashes::default()));
+    let write_peer = peer.clone();
+
+    // Writer task, both the file watcher and replies to the server go through here
+    let writer_task = tokio::spawn(async move {
+        while let Some(outgoing) = rx.recv().await {
+            if let Err(e) = write_outgoing(&mut writer, &outgoing, &session, &write_peer).await {
+                eprintln!("Failed to write to server: {:?}", e);
+                if e.is_disconnected() {
+                    break;
+                }
+            }
+        }
+    });
+
+    // Keep the journal up to date, so whatever happens while we're disconnected can be reconciled later
+    let journal_file_watcher = file_watcher.clone();
+    let journal_task = tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(JOURNAL_INTERVAL).await;
+            journal_file_watcher.lock().unwrap().update_journal();
+        }
+    });
+
+    // Pushing our tree, so we go first. Our changes can go out right away, there's nothing to reconcile.
+    let (authority, direction) = {
+        let file_watcher = file_watcher.lock().unwrap();
+        (file_watcher.authority(), file_watcher.direction())
+    };
+    let source = direction.source(authority);
+    if source == Authority::Client {
+        let manifest = file_watcher.lock().unwrap().get_manifest();
+        if tx.send(Outgoing::Message(manifest)).is_err() {
+            eprintln!("Failed to send manifest to {}", addr);
+        }
+        *connection.lock().unwrap() = Some(tx.clone());
+    }
+
+    // Server file update reader, until the server goes away or we refuse its tree
+    let mut transfers = PeerTransfers::new(file_watcher.clone());
+    let refused = 'read: loop {
         let msg = read_msg(&mut reader).await;
         if let Err(e) = msg {
             eprintln!("Failed to read message from {}: {:?}", addr, e);
             if e.is_disconnected() {
-                break;
+                break false;
             }
             continue;
         }
 
         let msg = msg.unwrap();
-        file_watcher2.lock().unwrap().handle_message(&msg, false);
+        if !direction.sends(authority, Authority::Server) && msg.is_change() {
+            eprintln!("Ignoring a change from {}, changes only go to the server ({})", addr, direction);
+            continue;
+        }
+        if let Err(e) = transfers.track(&msg) {
+            eprintln!("Rejecting message from {}: {}", addr, e);
+            if tx.send(Outgoing::Message(MessageType::Error { message: format!("Rejected: {}", e) })).is_err() {
+                eprintln!("Failed to reply to {}", addr);
+            }
+            continue;
+        }
+
+        let is_authorative = source == Authority::Client;
+        let (replies, refused) = {
+            let mut file_watcher = file_watcher.lock().unwrap();
+            let replies = file_watcher.handle_message(&msg, is_authorative, &mut peer.lock().unwrap());
+            (replies, file_watcher.take_refused())
+        };
+        for reply in replies {
+            if tx.send(reply).is_err() {
+                eprintln!("Failed to reply to {}", addr);
+            }
+        }
+        if refused {
+            eprintln!("Refused the server's tree, disconnecting");
+            break 'read true;
+        }
+
+        // Anything that changed before the manifest came in was part of reconciling it,
+        // sending it before then would only look like a conflict to the server
+        if let MessageType::Manifest { .. } = msg {
+            *connection.lock().unwrap() = Some(tx.clone());
+        }
+    };
+
+    *connection.lock().unwrap() = None;
+    if refused {
+        // Let the server know why before hanging up
+        drop(tx);
+        if let Err(e) = writer_task.await {
+            eprintln!("Failed to finish writing to {}: {:?}", addr, e);
+        }
+    } else {
+        writer_task.abort();
     }
+    journal_task.abort();
+    file_watcher.lock().unwrap().disconnected();
+    eprintln!("Disconnected from {}", addr);
+}
 
-    eprintln!("Reader closed");
-    std::process::exit(0);
-}
\ No newline at end of file
+// Delays between reconnect attempts. Each one is jittered, so clients that lost the same
+// server don't all come back at the same moment.
+struct Backoff {
+    delay: Duration,
+}
+
+impl Backoff {
+    fn new() -> Self {
+        Backoff { delay: RECONNECT_DELAY }
+    }
+
+    fn reset(&mut self) {
+        self.delay = RECONNECT_DELAY;
+    }
+
+    // Somewhere between half and all of the current delay
+    fn next_delay(&mut self) -> Duration {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64;
+        let jitter = (fnv1a64(&now.to_be_bytes()) % 1000) as f64 / 1000.0;
+        let delay = self.delay.mul_f64(0.5 + jitter / 2.0);
+        self.delay = (self.delay * 2).min(MAX_RECONNECT_DELAY);
+        delay
+    }
+}
@@ -1,13 +1,64 @@
 use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+use crate::acl::Access;
+use crate::auth::{self, ClientToken};
+use crate::brake::DeleteBrake;
+use crate::trash::TrashOptions;
+use crate::protocol::{Authority, Direction};
+use crate::symlink::SymlinkMode;
+use crate::tls::{self, ClientTls, ServerTls};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub(crate) struct ServerConfig {
     pub(crate) port: u16,
     pub(crate) location: String,
+
+    // IP addresses to listen on, each optionally with a port of its own, like `127.0.0.1`,
+    // `[::1]:5344` or `0.0.0.0`. Addresses without a port use `port`.
+    #[serde(default = "default_bind")]
+    pub(crate) bind: Vec<String>,
+
+    // Without it, connections are plain TCP
+    #[serde(default)]
+    pub(crate) tls: Option<ServerTls>,
+
+    // Clients that may connect, and what they may do. Without it anyone can do anything,
+    // see `auth.rs` and `acl.rs`. Changes are picked up without a restart.
+    #[serde(default)]
+    pub(crate) tokens: Option<Vec<ClientToken>>,
+
+    // No client can change anything, whatever its token allows
+    #[serde(default)]
+    pub(crate) read_only: bool,
+
+    #[serde(flatten)]
+    pub(crate) options: SyncOptions,
 }
 
 impl ServerConfig {
     pub(crate) const DEFAULT_PORT: u16 = 5343;
+
+    pub(crate) fn bind_addresses(&self) -> Result<Vec<SocketAddr>, String> {
+        if self.bind.is_empty() {
+            return Err("No addresses to listen on".to_string());
+        }
+
+        self.bind
+            .iter()
+            .map(|bind| {
+                let (host, port) = parse_address(bind)?;
+                let ip = host
+                    .parse::<IpAddr>()
+                    .map_err(|_| format!("'{}' is not an IP address", host))?;
+                Ok(SocketAddr::new(ip, port.unwrap_or(self.port)))
+            })
+            .collect()
+    }
+}
+
+// Every interface, like before the bind addresses were configurable
+fn default_bind() -> Vec<String> {
+    vec!["0.0.0.0".to_string()]
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -15,6 +66,146 @@ pub(crate) struct ClientConfig {
     pub(crate) host: String,
     pub(crate) port: u16,
     pub(crate) location: String,
+
+    // Must be set if the server uses TLS, and only then
+    #[serde(default)]
+    pub(crate) tls: Option<ClientTls>,
+
+    // One of the server's `tokens`, if it has any
+    #[serde(default)]
+    pub(crate) token: Option<String>,
+
+    // Never send anything to the server, only take its changes
+    #[serde(default)]
+    pub(crate) read_only: bool,
+
+    // With `read_only`, also put back the server's version of anything changed here
+    #[serde(default)]
+    pub(crate) revert_local_changes: bool,
+
+    // What to do with files we have that the server doesn't, when we first sync with it
+    #[serde(default)]
+    pub(crate) extra_files: ExtraFiles,
+
+    #[serde(flatten)]
+    pub(crate) options: SyncOptions,
+}
+
+impl ClientConfig {
+    pub(crate) fn local_changes(&self) -> LocalChanges {
+        let (authority, direction) = (self.options.authority, self.options.direction);
+        if direction == Direction::Mirror && direction.source(authority) == Authority::Server {
+            return LocalChanges::Revert;
+        }
+        if !direction.sends(authority, Authority::Client) {
+            return if self.revert_local_changes { LocalChanges::Revert } else { LocalChanges::Keep };
+        }
+
+        match (self.read_only, self.revert_local_changes) {
+            (false, _) => LocalChanges::Send,
+            (true, false) => LocalChanges::Keep,
+            (true, true) => LocalChanges::Revert,
+        }
+    }
+
+    // The host without the brackets an IPv6 address may be written with.
+    pub(crate) fn host(&self) -> &str {
+        let host = self.host.trim();
+        host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host)
+    }
+}
+
+// What a client does with changes made on its side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum LocalChanges {
+    #[default]
+    Send,
+    // Left alone, the server never hears of them
+    Keep,
+    // Undone, by fetching the server's copy or deleting what the server doesn't have
+    Revert,
+}
+
+// Files a client has that the server doesn't list when they first sync. Those are either left
+// from before we ever synced, or were deleted on the server while we were away. Files created
+// or changed here while we were away are local changes instead, see `LocalChanges`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ExtraFiles {
+    // Left alone, with a line in the log
+    #[default]
+    Report,
+    // Deleted, the server's tree is what counts
+    Delete,
+    // Sent to the server, so they're back on every side
+    Upload,
+}
+
+// Splits `host`, `host:port`, `[ipv6]` or `[ipv6]:port` into the host and the port, if any.
+// An IPv6 address without brackets is taken as a host without a port.
+pub(crate) fn parse_address(address: &str) -> Result<(String, Option<u16>), String> {
+    let parse_port = |port: &str| port.parse::<u16>().map_err(|_| format!("Invalid port '{}'", port));
+
+    if let Some(rest) = address.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']').ok_or(format!("Missing ']' in '{}'", address))?;
+        return match rest {
+            "" => Ok((host.to_string(), None)),
+            _ => match rest.strip_prefix(':') {
+                Some(port) => Ok((host.to_string(), Some(parse_port(port)?))),
+                None => Err(format!("Unexpected '{}' after ']' in '{}'", rest, address)),
+            },
+        };
+    }
+
+    match address.split_once(':') {
+        Some((host, port)) if !port.contains(':') => Ok((host.to_string(), Some(parse_port(port)?))),
+        _ => Ok((address.to_string(), None)),
+    }
+}
+
+// Settings that change how files are synced, shared by both sides.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct SyncOptions {
+    // Sync file permissions and modification times, not just contents
+    #[serde(default = "default_preserve_metadata")]
+    pub(crate) preserve_metadata: bool,
+
+    #[serde(default)]
+    pub(crate) symlinks: SymlinkMode,
+
+    // Whose tree is the source of truth, the server's or the client's
+    #[serde(default)]
+    pub(crate) authority: Authority,
+
+    // Which way changes go. A server only lets clients sync the way it's set to, but a two-way
+    // server lets them pick anything other than mirroring.
+    #[serde(default)]
+    pub(crate) direction: Direction,
+
+    // Without it, any number of deletes goes through
+    #[serde(default)]
+    pub(crate) delete_brake: Option<DeleteBrake>,
+
+    // Without it, what the other side overwrites or deletes is gone for good
+    #[serde(default)]
+    pub(crate) trash: Option<TrashOptions>,
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        SyncOptions {
+            preserve_metadata: default_preserve_metadata(),
+            symlinks: SymlinkMode::default(),
+            authority: Authority::default(),
+            direction: Direction::default(),
+            delete_brake: None,
+            trash: None,
+        }
+    }
+}
+
+fn default_preserve_metadata() -> bool {
+    true
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -25,11 +216,21 @@ pub(crate) enum Config {
 
 impl Config {
     pub(crate) fn new_server(port: u16, location: String) -> Self {
-        Config::Server(ServerConfig { port, location })
+        Config::Server(ServerConfig { port, location, bind: default_bind(), tls: None, tokens: None, read_only: false, options: SyncOptions::default() })
     }
 
     pub(crate) fn new_client(host: String, port: u16, location: String) -> Self {
-        Config::Client(ClientConfig { host, port, location })
+        Config::Client(ClientConfig {
+            host,
+            port,
+            location,
+            tls: None,
+            token: None,
+            read_only: false,
+            revert_local_changes: false,
+            extra_files: ExtraFiles::default(),
+            options: SyncOptions::default(),
+        })
     }
 
     pub(crate) fn from_file(file_path: &str) -> Result<Self, Box<dyn std::error::Error>> {
@@ -48,16 +249,111 @@ impl Config {
         let config = Config::Server(ServerConfig {
             port: ServerConfig::DEFAULT_PORT,
             location: String::from("/path/to/server"),
+            bind: default_bind(),
+            tls: None,
+            tokens: None,
+            read_only: false,
+            options: SyncOptions::default(),
         });
         config.to_file("config.json").unwrap();
     }
 
+    // Like `create_server_config`, but with a freshly generated certificate, and a client config
+    // that pins it and has a token for it. Returns the certificate's fingerprint.
+    pub(crate) fn create_tls_server_config() -> Result<String, Box<dyn std::error::Error>> {
+        let certificate = String::from("server.crt");
+        let key = String::from("server.key");
+        if std::fs::exists(&key)? {
+            return Err(format!("'{}' already exists, not replacing the key clients have pinned", key).into());
+        }
+        let fingerprint = tls::generate_certificate(&certificate, &key)?;
+        let token = auth::generate_token()?;
+
+        let server = Config::Server(ServerConfig {
+            port: ServerConfig::DEFAULT_PORT,
+            location: String::from("/path/to/server"),
+            bind: default_bind(),
+            tls: Some(ServerTls { certificate, key }),
+            tokens: Some(vec![ClientToken {
+                name: String::from("client"),
+                token: token.clone(),
+                access: Access::default(),
+            }]),
+            read_only: false,
+            options: SyncOptions::default(),
+        });
+        server.to_file("config.json")?;
+
+        let client = Config::Client(ClientConfig {
+            host: String::from("localhost"),
+            port: ServerConfig::DEFAULT_PORT,
+            location: String::from("/path/to/client"),
+            tls: Some(ClientTls { fingerprint: fingerprint.clone() }),
+            token: Some(token),
+            read_only: false,
+            revert_local_changes: false,
+            extra_files: ExtraFiles::default(),
+            options: SyncOptions::default(),
+        });
+        client.to_file("client.json")?;
+        Ok(fingerprint)
+    }
+
     pub(crate) fn create_client_config() {
         let config = Config::Client(ClientConfig {
             host: String::from("localhost"),
             port: ServerConfig::DEFAULT_PORT,
             location: String::from("/path/to/client"),
+            tls: None,
+            token: None,
+            read_only: false,
+            revert_local_changes: false,
+            extra_files: ExtraFiles::default(),
+            options: SyncOptions::default(),
         });
         config.to_file("config.json").unwrap();
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(address: &str) -> (String, Option<u16>) {
+        parse_address(address).unwrap()
+    }
+
+    #[test]
+    fn parses_hosts_with_and_without_a_port() {
+        assert_eq!(parsed("example.com"), ("example.com".to_string(), None));
+        assert_eq!(parsed("example.com:5344"), ("example.com".to_string(), Some(5344)));
+        assert_eq!(parsed("127.0.0.1:5344"), ("127.0.0.1".to_string(), Some(5344)));
+    }
+
+    #[test]
+    fn parses_bracketed_ipv6_addresses() {
+        assert_eq!(parsed("[::1]"), ("::1".to_string(), None));
+        assert_eq!(parsed("[::1]:5344"), ("::1".to_string(), Some(5344)));
+        assert_eq!(parsed("[fe80::1%eth0]:22"), ("fe80::1%eth0".to_string(), Some(22)));
+    }
+
+    #[test]
+    fn takes_a_bare_ipv6_address_as_a_host() {
+        assert_eq!(parsed("::1"), ("::1".to_string(), None));
+        assert_eq!(parsed("2001:db8::5344"), ("2001:db8::5344".to_string(), None));
+    }
+
+    #[test]
+    fn refuses_invalid_ports() {
+        assert!(parse_address("example.com:").is_err());
+        assert!(parse_address("example.com:http").is_err());
+        assert!(parse_address("example.com:65536").is_err());
+        assert!(parse_address("[::1]:").is_err());
+    }
+
+    #[test]
+    fn refuses_malformed_brackets() {
+        assert!(parse_address("[::1").is_err());
+        assert!(parse_address("[::1]5344").is_err());
+    }
+}
@@ -0,0 +1,161 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::file_watcher::fnv1a64;
+use crate::message_handler::MessageType;
+
+// What we believe the other end of a connection has, as the hash of each file by relative path.
+// It's updated with everything we send or receive, so it's only ever wrong when changes cross
+// on the wire, which is exactly when the receiver should notice a conflict.
+// Every connection has its own, as different clients can be at different versions.
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq)]
+pub(crate) struct PeerHashes {
+    hashes: HashMap<String, u64>,
+}
+
+impl PeerHashes {
+    // The parent for a change to `path`, 0 if we don't think the peer has it at all.
+    pub fn parent_of(&self, path: &str) -> u64 {
+        self.hashes.get(path).copied().unwrap_or(0)
+    }
+
+    pub fn get(&self, path: &str) -> Option<u64> {
+        self.hashes.get(path).copied()
+    }
+
+    // Everything inside of a directory, not counting the directory itself.
+    pub fn paths_under(&self, path: &str) -> Vec<String> {
+        let prefix = format!("{}/", path);
+        self.hashes.keys().filter(|p| p.starts_with(&prefix)).cloned().collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    pub fn set(&mut self, path: &str, hash: u64) {
+        self.hashes.insert(path.to_string(), hash);
+    }
+
+    // Forgets a file, or a directory and everything inside of it.
+    pub fn remove(&mut self, path: &str) {
+        let prefix = format!("{}/", path);
+        self.hashes.retain(|p, _| p != path && !p.starts_with(&prefix));
+    }
+
+    pub fn rename(&mut self, from: &str, to: &str) {
+        let from_prefix = format!("{}/", from);
+        self.remove(to);
+        self.hashes = self
+            .hashes
+            .drain()
+            .map(|(path, hash)| {
+                if path == from {
+                    return (to.to_string(), hash);
+                }
+                match path.strip_prefix(&from_prefix) {
+                    Some(rest) => (format!("{}/{}", to, rest), hash),
+                    None => (path, hash),
+                }
+            })
+            .collect();
+    }
+
+    // Called right before a message goes out. Changes get the hash the peer has as their parent,
+    // and we remember what the peer will have once it's applied them.
+    pub fn stamp(&mut self, msg: &mut MessageType) {
+        match msg {
+            MessageType::CreateEvent { path, contents, parent_hash, .. }
+            | MessageType::ModifyEvent { path, contents, parent_hash, .. } => {
+                *parent_hash = Some(self.parent_of(path));
+                self.set(path, fnv1a64(contents));
+            }
+            MessageType::DeltaEvent { path, hash, parent_hash, .. } => {
+                *parent_hash = Some(self.parent_of(path));
+                self.set(path, *hash);
+            }
+            MessageType::Sync { files, .. } => {
+                for (path, contents) in files {
+                    self.set(path, fnv1a64(contents));
+                }
+            }
+            MessageType::Manifest { entries, .. } => {
+                // The peer will fetch whatever it doesn't have yet
+                for entry in entries {
+                    self.set(&entry.path, entry.hash);
+                }
+            }
+            MessageType::MoveEvent { old_path, new_path, parent_hash } => {
+                *parent_hash = Some(self.parent_of(new_path));
+                self.rename(old_path, new_path);
+            }
+            MessageType::DeleteEvent { path, parent_hash } => {
+                *parent_hash = Some(self.parent_of(path));
+                self.remove(path);
+            }
+            MessageType::DeleteDir { path, parent_hashes } => {
+                let under = self.paths_under(path);
+                *parent_hashes = Some(under.into_iter().map(|p| (p.clone(), self.parent_of(&p))).collect());
+                self.remove(path);
+            }
+            _ => {}
+        }
+    }
+}
+
+// Where to keep our copy of a file when a change from the other side wasn't based on it,
+// e.g. `notes.conflict-laptop-20250102-153000.txt` next to `notes.txt`.
+pub(crate) fn conflict_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let suffix = format!("conflict-{}-{}", hostname(), timestamp());
+    let name = match path.extension() {
+        Some(extension) => format!("{}.{}.{}", stem, suffix, extension.to_string_lossy()),
+        None => format!("{}.{}", stem, suffix),
+    };
+    path.with_file_name(name)
+}
+
+fn hostname() -> String {
+    let name = std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::env::var("COMPUTERNAME").ok())
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .unwrap_or_default();
+
+    // It ends up in a file name, so keep it to something every filesystem accepts
+    let name: String = name
+        .trim()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    if name.is_empty() { "unknown".to_string() } else { name }
+}
+
+// The current UTC time as YYYYMMDD-HHMMSS.
+fn timestamp() -> String {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days, secs) = (secs / 86400, secs % 86400);
+
+    // Days since the epoch to a civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
@@ -0,0 +1,412 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::file_watcher::{FNV1A64_INIT, fnv1a64, fnv1a64_update};
+
+// Files smaller than this are always sent whole, the round trip isn't worth it.
+pub(crate) const DELTA_THRESHOLD: u64 = 64 * 1024;
+
+// Anything larger is streamed whole, neither side reads a file for signatures past this.
+pub(crate) const DELTA_MAX_SIZE: u64 = 64 * 1024 * 1024;
+
+// How much of a file is read at once while computing a delta.
+const READ_SIZE: usize = 64 * 1024;
+
+const MIN_BLOCK_SIZE: usize = 1024;
+const MAX_BLOCK_SIZE: usize = 64 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct BlockSignature {
+    pub weak: u32,
+    pub strong: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) enum DeltaOp {
+    // Copy `count` blocks from the receiver's copy, starting at block `index`
+    Copy { index: u64, count: u64 },
+    Data { bytes: Vec<u8> },
+}
+
+// Roughly the square root of the file size, like rsync does.
+fn block_size_for(len: u64) -> usize {
+    let size = (len as f64).sqrt() as usize;
+    size.next_multiple_of(64).clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)
+}
+
+// Adler-32 style checksum that can be rolled forward one byte at a time.
+fn weak_checksum(block: &[u8]) -> (u32, u32) {
+    let mut a: u32 = 0;
+    let mut b: u32 = 0;
+    let len = block.len() as u32;
+    for (i, &x) in block.iter().enumerate() {
+        a = a.wrapping_add(x as u32);
+        b = b.wrapping_add((len - i as u32).wrapping_mul(x as u32));
+    }
+    (a & 0xffff, b & 0xffff)
+}
+
+fn combine(a: u32, b: u32) -> u32 {
+    a | (b << 16)
+}
+
+fn roll(a: u32, b: u32, out: u8, inp: u8, block_size: usize) -> (u32, u32) {
+    let a = a.wrapping_sub(out as u32).wrapping_add(inp as u32) & 0xffff;
+    let b = b
+        .wrapping_sub((block_size as u32).wrapping_mul(out as u32))
+        .wrapping_add(a)
+        & 0xffff;
+    (a, b)
+}
+
+// Describes a file of `len` bytes in blocks, reading it one block at a time. Also returns the
+// hash of the whole file.
+pub(crate) fn signatures(reader: &mut impl Read, len: u64) -> std::io::Result<(u32, Vec<BlockSignature>, u64)> {
+    let block_size = block_size_for(len);
+    let mut blocks = Vec::new();
+    let mut hash = FNV1A64_INIT;
+    let mut block = Vec::with_capacity(block_size);
+    loop {
+        block.clear();
+        reader.by_ref().take(block_size as u64).read_to_end(&mut block)?;
+        if block.is_empty() {
+            return Ok((block_size as u32, blocks, hash));
+        }
+
+        let (a, b) = weak_checksum(&block);
+        blocks.push(BlockSignature { weak: combine(a, b), strong: fnv1a64(&block) });
+        hash = fnv1a64_update(hash, &block);
+    }
+}
+
+// What the sender's copy looks like in terms of the receiver's blocks, see `compute_delta`.
+pub(crate) struct Delta {
+    pub ops: Vec<DeltaOp>,
+    pub size: u64,
+    pub hash: u64,
+}
+
+// Reads ahead of the window `compute_delta` is looking at, hashing everything that goes by.
+struct Input<'a, R: Read> {
+    reader: &'a mut R,
+    buf: Vec<u8>,
+    eof: bool,
+    size: u64,
+    hash: u64,
+}
+
+impl<R: Read> Input<'_, R> {
+    // Reads until there are at least `len` bytes in the buffer, or there's nothing left.
+    fn fill(&mut self, len: usize) -> std::io::Result<()> {
+        let mut chunk = vec![0u8; READ_SIZE];
+        while !self.eof && self.buf.len() < len {
+            let read = match self.reader.read(&mut chunk) {
+                Ok(read) => read,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if read == 0 {
+                self.eof = true;
+                break;
+            }
+            self.buf.extend_from_slice(&chunk[..read]);
+            self.size += read as u64;
+            self.hash = fnv1a64_update(self.hash, &chunk[..read]);
+        }
+        Ok(())
+    }
+}
+
+// Describes what `reader` has in terms of blocks the other side already has, plus whatever's
+// new. Only what hasn't been matched yet is kept in memory, and once more than `max_literal`
+// bytes of that add up, it gives up and returns None, the file is better off sent whole.
+pub(crate) fn compute_delta<R: Read>(
+    reader: &mut R,
+    block_size: u32,
+    blocks: &[BlockSignature],
+    max_literal: u64,
+) -> std::io::Result<Option<Delta>> {
+    let block_size = block_size as usize;
+    let mut by_weak: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (index, block) in blocks.iter().enumerate() {
+        by_weak.entry(block.weak).or_default().push(index);
+    }
+
+    let push_copy = |ops: &mut Vec<DeltaOp>, index: usize| {
+        if let Some(DeltaOp::Copy { index: start, count }) = ops.last_mut()
+            && *start + *count == index as u64
+        {
+            *count += 1;
+            return;
+        }
+        ops.push(DeltaOp::Copy { index: index as u64, count: 1 });
+    };
+
+    let mut input = Input { reader, buf: Vec::new(), eof: false, size: 0, hash: FNV1A64_INIT };
+    let mut ops = Vec::new();
+    let mut literal: u64 = 0;
+    let mut pos = 0;
+    let mut literal_start = 0;
+    let mut checksum = None;
+    loop {
+        // Everything before the current literal has been dealt with
+        if literal_start >= READ_SIZE {
+            input.buf.drain(..literal_start);
+            pos -= literal_start;
+            literal_start = 0;
+        }
+        if literal + (pos - literal_start) as u64 > max_literal {
+            return Ok(None);
+        }
+
+        input.fill(pos + block_size + 1)?;
+        if block_size == 0 || input.buf.len() < pos + block_size {
+            break;
+        }
+
+        let window = &input.buf[pos..pos + block_size];
+        let (a, b) = *checksum.get_or_insert_with(|| weak_checksum(window));
+        let matched = by_weak.get(&combine(a, b)).and_then(|candidates| {
+            let strong = fnv1a64(window);
+            candidates.iter().copied().find(|&i| blocks[i].strong == strong)
+        });
+
+        if let Some(index) = matched {
+            if literal_start < pos {
+                ops.push(DeltaOp::Data { bytes: input.buf[literal_start..pos].to_vec() });
+                literal += (pos - literal_start) as u64;
+            }
+            push_copy(&mut ops, index);
+            pos += block_size;
+            literal_start = pos;
+            checksum = None;
+            continue;
+        }
+
+        if pos + block_size < input.buf.len() {
+            checksum = Some(roll(a, b, input.buf[pos], input.buf[pos + block_size], block_size));
+        }
+        pos += 1;
+    }
+
+    // Whatever's left didn't match anything
+    let allowed = usize::try_from(max_literal.saturating_sub(literal)).unwrap_or(usize::MAX);
+    input.fill(literal_start.saturating_add(allowed).saturating_add(1))?;
+    let tail = input.buf.len() - literal_start;
+    if tail > allowed {
+        return Ok(None);
+    }
+    if tail > 0 {
+        ops.push(DeltaOp::Data { bytes: input.buf[literal_start..].to_vec() });
+    }
+    Ok(Some(Delta { ops, size: input.size, hash: input.hash }))
+}
+
+pub(crate) fn literal_size(ops: &[DeltaOp]) -> u64 {
+    ops.iter()
+        .map(|op| match op {
+            DeltaOp::Data { bytes } => bytes.len() as u64,
+            DeltaOp::Copy { .. } => 0,
+        })
+        .sum()
+}
+
+// Rebuilds the sender's copy from our `base` of `base_len` bytes, writing it to `out`. The ops
+// come from the other side, so they can't refer to blocks we don't have, or add up to anything
+// but the `size` the sender announced.
+pub(crate) fn apply_delta<B, W>(base: &mut B, base_len: u64, block_size: u32, ops: &[DeltaOp], size: u64, out: &mut W) -> Result<(), String>
+where
+    B: Read + Seek,
+    W: Write,
+{
+    let block_size = block_size as u64;
+    let mut written: u64 = 0;
+    for op in ops {
+        let len = match op {
+            DeltaOp::Copy { index, count } => {
+                let start = index.checked_mul(block_size);
+                let end = count.checked_mul(block_size).zip(start).and_then(|(len, start)| start.checked_add(len));
+                let (Some(start), Some(end)) = (start, end) else {
+                    return Err(format!("Delta refers to blocks {} to {}, which are out of range", index, index.saturating_add(*count)));
+                };
+                let end = end.min(base_len);
+                if start >= end {
+                    return Err(format!("Delta refers to block {} which we don't have", index));
+                }
+                end - start
+            }
+            DeltaOp::Data { bytes } => bytes.len() as u64,
+        };
+
+        written = written
+            .checked_add(len)
+            .filter(|written| *written <= size)
+            .ok_or(format!("Delta adds up to more than the {} bytes it announced", size))?;
+
+        match op {
+            DeltaOp::Copy { index, .. } => {
+                base.seek(SeekFrom::Start(index * block_size)).map_err(|e| format!("Failed to read our copy: {:?}", e))?;
+                let copied = std::io::copy(&mut base.by_ref().take(len), out).map_err(|e| format!("Failed to write: {:?}", e))?;
+                if copied != len {
+                    return Err("Our copy changed while applying the delta".to_string());
+                }
+            }
+            DeltaOp::Data { bytes } => out.write_all(bytes).map_err(|e| format!("Failed to write: {:?}", e))?,
+        }
+    }
+
+    if written != size {
+        return Err(format!("Delta adds up to {} bytes, not the {} it announced", written, size));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn sample(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    fn diff(base: &[u8], target: &[u8]) -> (u32, Vec<DeltaOp>) {
+        let (block_size, blocks, hash) = signatures(&mut Cursor::new(base), base.len() as u64).unwrap();
+        assert_eq!(hash, fnv1a64(base));
+        let delta = compute_delta(&mut Cursor::new(target), block_size, &blocks, u64::MAX).unwrap().unwrap();
+        assert_eq!(delta.size, target.len() as u64);
+        assert_eq!(delta.hash, fnv1a64(target));
+        (block_size, delta.ops)
+    }
+
+    // Hands out a few bytes at a time, like a slow pipe.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(self.0.len()).min(7);
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Ok(len)
+        }
+    }
+
+    fn apply(base: &[u8], block_size: u32, ops: &[DeltaOp], size: u64) -> Result<Vec<u8>, String> {
+        let mut out = Vec::new();
+        apply_delta(&mut Cursor::new(base), base.len() as u64, block_size, ops, size, &mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn round_trips_an_edit_in_the_middle() {
+        let base = sample(200 * 1024, 1);
+        let mut target = base.clone();
+        target.splice(100_000..100_010, b"something else entirely".iter().copied());
+
+        let (block_size, ops) = diff(&base, &target);
+        assert!(literal_size(&ops) < 8 * 1024, "only the changed blocks should be sent");
+        assert_eq!(apply(&base, block_size, &ops, target.len() as u64).unwrap(), target);
+    }
+
+    #[test]
+    fn round_trips_insertions_at_either_end() {
+        let base = sample(100 * 1024, 2);
+        let target = [b"header".as_slice(), &base, b"trailer"].concat();
+
+        let (block_size, ops) = diff(&base, &target);
+        assert_eq!(apply(&base, block_size, &ops, target.len() as u64).unwrap(), target);
+    }
+
+    #[test]
+    fn round_trips_unrelated_contents() {
+        let base = sample(80 * 1024, 3);
+        let target = sample(90 * 1024, 4);
+
+        let (block_size, ops) = diff(&base, &target);
+        assert_eq!(literal_size(&ops), target.len() as u64);
+        assert_eq!(apply(&base, block_size, &ops, target.len() as u64).unwrap(), target);
+    }
+
+    #[test]
+    fn round_trips_a_target_smaller_than_a_block() {
+        let base = sample(100 * 1024, 5);
+        let target = b"short".to_vec();
+
+        let (block_size, ops) = diff(&base, &target);
+        assert_eq!(apply(&base, block_size, &ops, target.len() as u64).unwrap(), target);
+    }
+
+    #[test]
+    fn round_trips_a_target_read_in_small_pieces() {
+        let base = sample(300 * 1024, 11);
+        let mut target = base.clone();
+        target.splice(150_000..150_000, sample(5000, 12));
+        target.truncate(290 * 1024);
+
+        let (block_size, blocks, _) = signatures(&mut Cursor::new(&base), base.len() as u64).unwrap();
+        let delta = compute_delta(&mut Trickle(&target), block_size, &blocks, u64::MAX).unwrap().unwrap();
+        assert_eq!(delta.hash, fnv1a64(&target));
+        assert!(literal_size(&delta.ops) < 16 * 1024);
+        assert_eq!(apply(&base, block_size, &delta.ops, delta.size).unwrap(), target);
+    }
+
+    #[test]
+    fn gives_up_once_too_much_changed() {
+        let base = sample(100 * 1024, 13);
+        let target = sample(100 * 1024, 14);
+        let (block_size, blocks, _) = signatures(&mut Cursor::new(&base), base.len() as u64).unwrap();
+
+        assert!(compute_delta(&mut Cursor::new(&target), block_size, &blocks, 10 * 1024).unwrap().is_none());
+        assert!(compute_delta(&mut Cursor::new(&target), block_size, &blocks, 100 * 1024).unwrap().is_some());
+        // Not even a block to compare with, it all counts
+        assert!(compute_delta(&mut Cursor::new(&target), 0, &[], 10 * 1024).unwrap().is_none());
+        assert!(compute_delta(&mut Cursor::new(&target), 0, &[], 100 * 1024).unwrap().is_some());
+    }
+
+    #[test]
+    fn rejects_blocks_past_the_end_of_the_base() {
+        let base = sample(4096, 6);
+        let ops = [DeltaOp::Copy { index: 4, count: 1 }];
+        assert!(apply(&base, 1024, &ops, 1024).is_err());
+    }
+
+    #[test]
+    fn rejects_copies_that_overflow() {
+        let base = sample(4096, 7);
+        for (index, count) in [(u64::MAX, 1), (1, u64::MAX), (u64::MAX / 1024, 2)] {
+            let ops = [DeltaOp::Copy { index, count }];
+            assert!(apply(&base, 1024, &ops, 1024).is_err(), "index {} count {}", index, count);
+        }
+    }
+
+    #[test]
+    fn rejects_copies_without_a_block_size() {
+        let base = sample(4096, 8);
+        let ops = [DeltaOp::Copy { index: 0, count: 1 }];
+        assert!(apply(&base, 0, &ops, 1024).is_err());
+    }
+
+    #[test]
+    fn stops_once_the_announced_size_is_exceeded() {
+        let base = sample(4096, 9);
+        let ops = vec![DeltaOp::Copy { index: 0, count: 4 }; 1000];
+        let mut out = Vec::new();
+        let result = apply_delta(&mut Cursor::new(&base), base.len() as u64, 1024, &ops, 8192, &mut out);
+        assert!(result.is_err());
+        assert!(out.len() <= 8192);
+    }
+
+    #[test]
+    fn rejects_deltas_shorter_than_announced() {
+        let base = sample(4096, 10);
+        let ops = [DeltaOp::Copy { index: 0, count: 2 }];
+        assert!(apply(&base, 1024, &ops, 4096).is_err());
+    }
+}
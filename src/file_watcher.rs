@@ -1,14 +1,34 @@
-use ignore::Walk;
+use ignore::{Walk, WalkBuilder};
 use notify::Watcher;
-use std::path::Path;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::RecvTimeoutError;
 use std::sync::mpsc::{Receiver, channel};
-use std::{collections::HashMap, fs::create_dir_all};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::create_dir_all;
 
-use crate::message_handler::{MessageType, compose_data_message};
+use crate::message_handler::{ManifestEntry, MessageType, Outgoing};
+use crate::brake::{Brake, HeldDelete};
+use crate::config::{ExtraFiles, LocalChanges, SyncOptions};
+use crate::conflict::{PeerHashes, conflict_path};
+use crate::journal::Journal;
+use crate::metadata::FileMetadata;
+use crate::paths;
+use crate::protocol::{self, Authority, Direction};
+use crate::trash::Trash;
+use crate::symlink::{self, SymlinkMode};
+use crate::delta::{DELTA_MAX_SIZE, DELTA_THRESHOLD, apply_delta, signatures};
+use crate::transfer::{CHUNKED_THRESHOLD, IncomingTransfer, next_transfer_id};
 
-fn fnv1a64(bytes: &[u8]) -> u64 {
-    let mut state: u64 = 0xcbf29ce484222325;
+// How much file data a single `Sync` carries at most, see `get_requested_files`
+const SYNC_BATCH_SIZE: u64 = 4 * 1024 * 1024;
+
+// How long to wait for the second half of a rename before assuming the file left the tree
+const RENAME_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(100);
+
+pub(crate) const FNV1A64_INIT: u64 = 0xcbf29ce484222325;
+
+pub(crate) fn fnv1a64_update(mut state: u64, bytes: &[u8]) -> u64 {
     for &b in bytes {
         state = state.wrapping_mul(0x00000100000001b3);
         state ^= b as u64;
@@ -16,18 +36,79 @@ fn fnv1a64(bytes: &[u8]) -> u64 {
     state
 }
 
+pub(crate) fn fnv1a64(bytes: &[u8]) -> u64 {
+    fnv1a64_update(FNV1A64_INIT, bytes)
+}
+
+fn create_parent_dir(path: &str) {
+    if let Some(parent) = Path::new(path).parent()
+        && !parent.exists()
+        && let Err(e) = create_dir_all(parent)
+    {
+        eprintln!("Failed to create directory {}: {:?}", parent.display(), e);
+    }
+}
+
+// Hashes a file without reading all of it into memory at once.
+fn hash_file(path: &str) -> std::io::Result<u64> {
+    let mut file = std::fs::File::open(path)?;
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut state = FNV1A64_INIT;
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            return Ok(state);
+        }
+        state = fnv1a64_update(state, &buf[..read]);
+    }
+}
+
 pub(crate) struct FileWatcher {
     pub root: String,
 
     _watcher: notify::RecommendedWatcher,
-    files: Vec<String>,
+    files: HashSet<String>,
+    dirs: HashSet<String>,
     file_hashes: HashMap<String, u64>,
+    options: SyncOptions,
+    file_metadata: HashMap<String, FileMetadata>,
+    symlinks: HashMap<String, String>,
     events: Receiver<Result<notify::Event, notify::Error>>,
     ignore_files_until: HashMap<String, u64>,
+    transfers: HashMap<u64, IncomingTransfer>,
+    queued_events: VecDeque<Result<notify::Event, notify::Error>>,
+    pending_renames: HashMap<usize, (PathBuf, std::time::Instant)>,
+    completed_renames: HashSet<usize>,
+
+    // Changes a client made that the server should pass on to its other clients
+    forwards: Vec<Outgoing>,
+
+    // Only the client keeps a journal, see `journal.rs`. It describes the last connection
+    // until we've reconciled with the server's manifest, and is only updated after that.
+    journal: Option<Journal>,
+    journal_current: bool,
+    journal_changed: bool,
+
+    // Only a read-only client keeps or reverts its changes, see `ClientConfig::read_only`
+    local_changes: LocalChanges,
+
+    // See `ClientConfig::extra_files`
+    extra_files: ExtraFiles,
+
+    // Holds deletes once too many happen at once, see `brake.rs`
+    brake: Brake,
+
+    // Keeps what the other side overwrites or deletes, see `trash.rs`
+    trash: Option<Trash>,
+
+    // Set when we refused a manifest, the connection it came in on has to go
+    refused: bool,
+
+    is_server: bool,
 }
 
 impl FileWatcher {
-    pub fn new(root: &str) -> Result<Self, Box<dyn std::error::Error>> {
+    pub fn new(root: &str, options: SyncOptions) -> Result<Self, Box<dyn std::error::Error>> {
         let (tx, events) = channel();
         let mut _watcher = notify::recommended_watcher(tx)?;
 
@@ -39,81 +120,694 @@ impl FileWatcher {
 
         _watcher.watch(&root, notify::RecursiveMode::Recursive)?;
 
+        let brake = Brake::new(root.to_str().unwrap(), options.delete_brake.clone());
+        let trash = options.trash.clone().map(|trash| Trash::new(&root, trash)).transpose()?;
         let mut fw = FileWatcher {
             root: root.to_str().unwrap().to_string(),
             _watcher,
-            files: Vec::new(),
+            files: HashSet::new(),
+            dirs: HashSet::new(),
             file_hashes: HashMap::new(),
+            options,
+            file_metadata: HashMap::new(),
+            symlinks: HashMap::new(),
             events,
             ignore_files_until: HashMap::new(),
+            transfers: HashMap::new(),
+            queued_events: VecDeque::new(),
+            pending_renames: HashMap::new(),
+            completed_renames: HashSet::new(),
+            forwards: Vec::new(),
+            journal: None,
+            journal_current: false,
+            journal_changed: false,
+            local_changes: LocalChanges::Send,
+            extra_files: ExtraFiles::Report,
+            brake,
+            trash,
+            refused: false,
+            is_server: false,
         };
         fw.index_files();
         Ok(fw)
     }
 
-    pub fn handle_message(&mut self, msg: &MessageType, is_authorative: bool) {
+    // Applies a message from the other side. Some messages (like a file request) need an
+    // answer, which is returned so the caller can send it back to whoever asked.
+    // `is_authorative` is whether our tree is the source of truth on this connection, see `Authority`.
+    // On the server, changes that were applied are also kept for `take_forwards`.
+    // `peer` describes the connection the message came in on, see `PeerHashes`.
+    pub fn handle_message(&mut self, msg: &MessageType, is_authorative: bool, peer: &mut PeerHashes) -> Vec<Outgoing> {
+        // Every path the other side sends us is checked before we touch the filesystem with it
+        let msg = match paths::validate(Path::new(&self.root), msg) {
+            Ok(msg) => msg,
+            Err(e) => {
+                eprintln!("Rejecting message from peer: {}", e);
+                return vec![Outgoing::Message(MessageType::Error { message: format!("Rejected: {}", e) })];
+            }
+        };
+        let msg = msg.as_ref();
+
+        // Whatever changes again no longer gets deleted
+        if self.brake.is_holding() && msg.is_change() && !matches!(msg, MessageType::DeleteEvent { .. } | MessageType::DeleteDir { .. }) {
+            for (path, _) in paths::paths(msg) {
+                self.brake.cancel(path);
+            }
+        }
+
         let root = self.root.clone();
         let make_absolute_path = |path: &str| -> String {
             format!("{}/{}", root, path)
         };
+        let mut forward = None;
 
         match msg {
-            MessageType::Sync { files } => {
+            MessageType::Sync { files, metadata } => {
                 eprintln!("Received sync message");
                 if is_authorative {
                     eprintln!("Unexpected sync message from non-authoritative source");
-                    return;
+                    return vec![];
                 }
 
                 for file in files {
-                    let path = make_absolute_path(&file.0);
-
-                    // create parent paths
-                    if let Some(parent) = Path::new(&path).parent() {
-                        if !parent.exists() {
-                            create_dir_all(parent).unwrap_or_else(|_| {
-                                eprintln!("Failed to create directory: {}", parent.display());
-                            });
-                        }
-                    }
+                    let path = make_absolute_path(file.0);
+                    create_parent_dir(&path);
+                    self.keep_previous(file.0, Some(fnv1a64(file.1)));
 
-                    if let Err(e) = std::fs::write(&path, &file.1) {
+                    if let Err(e) = std::fs::write(&path, file.1) {
                         eprintln!("Failed to write file {}: {:?}", path, e);
+                        continue;
+                    }
+                    self.remember_file(&path, fnv1a64(file.1));
+                    self.confirm(peer, file.0, fnv1a64(file.1));
+                    self.apply_metadata(&path, metadata.get(file.0));
+                    self.mark_as_modified(file.0);
+
+                    // Pushed by a client, the other clients get them the way they'd get our own changes
+                    if self.is_server {
+                        let metadata = self.local_metadata(&path);
+                        self.forwards.push(Outgoing::File { path: file.0.clone(), abs_path: path, delta: true, metadata });
                     }
-                    self.mark_as_modified(&file.0);
                 }
 
                 eprintln!("Sync message processed, files written to '{}'", self.root);
             }
-            MessageType::CreateEvent { path, contents }
-            | MessageType::ModifyEvent { path, contents } => {
-                let abs_path = make_absolute_path(&path);
+            MessageType::CreateEvent { path, contents, metadata, parent_hash }
+            | MessageType::ModifyEvent { path, contents, metadata, parent_hash } => {
+                let abs_path = make_absolute_path(path);
+                let hash = fnv1a64(contents);
+                if let Some(replies) = self.resolve_conflict(&abs_path, *parent_hash, hash, is_authorative) {
+                    return replies;
+                }
+
+                create_parent_dir(&abs_path);
+                self.keep_previous(path, Some(hash));
                 if let Err(e) = std::fs::write(&abs_path, contents) {
                     eprintln!("Failed to write file {}: {:?}", path, e);
+                } else {
+                    self.remember_file(&abs_path, hash);
+                    self.confirm(peer, path, hash);
+                    self.apply_metadata(&abs_path, metadata.as_ref());
+                    forward = Some(Outgoing::Message(msg.clone()));
+                }
+                self.mark_as_modified(path);
+            }
+            MessageType::DeleteEvent { path, .. } | MessageType::DeleteDir { path, .. } => {
+                let dir = matches!(msg, MessageType::DeleteDir { .. });
+                let parents = match msg {
+                    MessageType::DeleteEvent { parent_hash: Some(hash), .. } => Some(HashMap::from([(path.clone(), *hash)])),
+                    MessageType::DeleteDir { parent_hashes, .. } => parent_hashes.clone(),
+                    _ => None,
+                };
+                if let Some(parents) = parents
+                    && let Some(replies) = self.resolve_delete_conflict(path, &parents, is_authorative, peer)
+                {
+                    return replies;
+                }
+
+                if !self.brake.allow(self.files_under(path), self.files.len()) {
+                    self.brake.hold(HeldDelete { path: path.clone(), dir, incoming: true });
+                    return vec![];
+                }
+
+                if self.apply_delete(path, dir) {
+                    forward = Some(Outgoing::Message(msg.clone()));
                 }
-                self.mark_as_modified(&path);
+                self.confirm_removed(peer, path);
             }
-            MessageType::DeleteEvent { path } => {
-                if let Err(e) = std::fs::remove_file(&path) {
-                    eprintln!("Failed to delete file {}: {:?}", path, e);
+            MessageType::CreateDir { path } => {
+                let abs_path = make_absolute_path(path);
+                if let Err(e) = create_dir_all(&abs_path) {
+                    eprintln!("Failed to create directory {}: {:?}", path, e);
+                    return vec![];
                 }
-                self.mark_as_modified(&path);
+                self.remember_dir(&abs_path);
+                self.mark_as_modified(path);
+                forward = Some(Outgoing::Message(msg.clone()));
             }
-            MessageType::MoveEvent { old_path, new_path } => {
-                let abs_old_path = make_absolute_path(&old_path);
-                let abs_new_path = make_absolute_path(&new_path);
+            MessageType::MoveEvent { old_path, new_path, parent_hash } => {
+                let abs_old_path = make_absolute_path(old_path);
+                let abs_new_path = make_absolute_path(new_path);
+                if old_path != new_path {
+                    let moved_hash = self.file_hashes.get(&abs_old_path).copied().unwrap_or(0);
+                    if let Some(mut replies) = self.resolve_conflict(&abs_new_path, *parent_hash, moved_hash, is_authorative) {
+                        // The move isn't made here, so the other side gets the file it moved back as well
+                        if is_authorative && Path::new(&abs_old_path).is_file() {
+                            replies.push(self.make_file_outgoing(&abs_old_path));
+                        }
+                        return replies;
+                    }
+                    self.keep_previous(new_path, None);
+                }
+
+                create_parent_dir(&abs_new_path);
+
                 if let Err(e) = std::fs::rename(&abs_old_path, &abs_new_path) {
                     eprintln!(
                         "Failed to move file from {} to {}: {:?}",
                         old_path, new_path, e
                     );
+                } else {
+                    self.rename_in_index(Path::new(&abs_old_path), Path::new(&abs_new_path));
+                    forward = Some(Outgoing::Message(msg.clone()));
+                }
+                self.confirm_renamed(peer, old_path, new_path);
+                self.mark_as_modified(old_path);
+                self.mark_as_modified(new_path);
+            }
+            MessageType::Error { message } => {
+                eprintln!("Peer reported an error: {}", message);
+            }
+            MessageType::Hello { .. }
+            | MessageType::Welcome { .. }
+            | MessageType::Authenticate { .. }
+            | MessageType::Authenticated { .. } => {
+                eprintln!("Unexpected handshake message after the connection was established");
+            }
+            MessageType::Manifest { entries, dirs, symlinks } => {
+                if is_authorative {
+                    eprintln!("Unexpected manifest message from non-authoritative source");
+                    return vec![];
+                }
+
+                // Most likely the wrong directory, like a mount that isn't there
+                if entries.is_empty() && !self.files.is_empty() && self.would_lose_files() {
+                    let reason = format!("The other side's tree is empty, while we have {} files", self.files.len());
+                    if self.brake.refuse_empty(reason.clone()) {
+                        self.refused = true;
+                        return vec![Outgoing::Message(MessageType::Error { message: format!("Refused: {}", reason) })];
+                    }
+                }
+
+                for dir in dirs {
+                    let abs_path = make_absolute_path(dir);
+                    if Path::new(&abs_path).is_dir() {
+                        continue;
+                    }
+
+                    if let Err(e) = create_dir_all(&abs_path) {
+                        eprintln!("Failed to create directory {}: {:?}", dir, e);
+                        continue;
+                    }
+                    self.remember_dir(&abs_path);
+                    self.mark_as_modified(dir);
+                }
+
+                for (path, target) in symlinks {
+                    if self.symlinks.get(&make_absolute_path(path)) != Some(target) {
+                        self.write_symlink(path, target);
+                    }
+                }
+
+                // Without a journal we've never been in sync before, so the server's copy wins.
+                // The server doesn't keep one, a client that pushes its tree always wins.
+                let (paths, mut replies) = match self.journal.take() {
+                    _ if self.is_server => (self.get_outdated_files(entries), vec![]),
+                    Some(journal) => self.reconcile(entries, journal),
+                    None => {
+                        let mut journal = Journal::default();
+                        for entry in entries {
+                            if self.file_hashes.get(&make_absolute_path(&entry.path)) == Some(&entry.hash) {
+                                journal.synced.set(&entry.path, entry.hash);
+                            }
+                        }
+                        self.journal = Some(journal);
+
+                        let on_server: HashSet<&str> = entries.iter().map(|entry| entry.path.as_str()).collect();
+                        let extras: Vec<String> = self
+                            .files
+                            .iter()
+                            .filter(|file| !on_server.contains(self.relative_path(file).as_str()))
+                            .cloned()
+                            .collect();
+                        let send = if self.local_changes == LocalChanges::Revert {
+                            extras.iter().for_each(|file| self.revert_local_file(file));
+                            vec![]
+                        } else {
+                            self.handle_extra_files(extras)
+                        };
+                        (self.get_outdated_files(entries), send)
+                    }
+                };
+                self.journal_current = !self.is_server;
+                self.journal_changed = !self.is_server;
+
+                // A mirror has nothing the other side doesn't
+                if self.options.direction == Direction::Mirror {
+                    self.remove_unlisted(entries, dirs, symlinks);
+                }
+
+                // This is what we'll have once we've fetched everything
+                for entry in entries {
+                    peer.set(&entry.path, entry.hash);
+                }
+
+                // Files we already have only need their metadata brought up to date
+                for entry in entries {
+                    let abs_path = make_absolute_path(&entry.path);
+                    if self.file_hashes.get(&abs_path) != Some(&entry.hash) {
+                        continue;
+                    }
+
+                    let metadata = FileMetadata { mode: entry.mode, mtime: entry.mtime };
+                    if self.local_metadata(&abs_path).is_some_and(|local| local != metadata) {
+                        self.apply_metadata(&abs_path, Some(&metadata));
+                        self.mark_as_modified(&entry.path);
+                    }
+                }
+
+                eprintln!("Received manifest of {} files, {} need to be fetched", entries.len(), paths.len());
+                if !paths.is_empty() {
+                    replies.insert(0, Outgoing::Message(MessageType::RequestFiles { paths }));
+                }
+                return replies;
+            }
+            MessageType::RequestFiles { paths } => {
+                if !is_authorative {
+                    eprintln!("Unexpected file request from authoritative source");
+                    return vec![];
+                }
+
+                eprintln!("Peer requested {} files", paths.len());
+                return self.get_requested_files(paths);
+            }
+            MessageType::TransferBegin { id, path, size, metadata, parent_hash } => {
+                if self.transfers.contains_key(id) {
+                    eprintln!("Transfer {:016x} is already under way, not starting it again", id);
+                    return vec![];
+                }
+
+                let abs_path = make_absolute_path(path);
+                match IncomingTransfer::begin(path, &abs_path, *id, *size, *metadata, *parent_hash) {
+                    Ok(transfer) => {
+                        eprintln!("Receiving {} ({} bytes)", path, size);
+                        self.transfers.insert(*id, transfer);
+                    }
+                    Err(e) => eprintln!("Failed to start receiving {}: {:?}", path, e),
+                }
+            }
+            MessageType::TransferChunk { id, offset, data } => {
+                let transfer = self.transfers.get_mut(id);
+                if transfer.is_none() {
+                    eprintln!("Received chunk for unknown transfer {:016x}", id);
+                    return vec![];
+                }
+
+                if let Err(e) = transfer.unwrap().write_chunk(*offset, data) {
+                    eprintln!("Aborting transfer {:016x}: {}", id, e);
+                    self.transfers.remove(id).unwrap().abort();
+                }
+            }
+            MessageType::TransferEnd { id, hash } => {
+                let transfer = self.transfers.remove(id);
+                if transfer.is_none() {
+                    eprintln!("Received end of unknown transfer {:016x}", id);
+                    return vec![];
+                }
+
+                let transfer = transfer.unwrap();
+                let path = transfer.path.clone();
+                let metadata = transfer.metadata;
+                let parent_hash = transfer.parent_hash;
+                let abs_path = make_absolute_path(&path);
+                let existed = self.files.contains(&abs_path);
+                if let Some(replies) = self.resolve_conflict(&abs_path, parent_hash, *hash, is_authorative) {
+                    transfer.abort();
+                    return replies;
+                }
+
+                self.keep_previous(&path, Some(*hash));
+                match transfer.finish(&abs_path, *hash) {
+                    Ok(hash) => {
+                        eprintln!("Received {}", path);
+                        self.remember_file(&abs_path, hash);
+                        self.confirm(peer, &path, hash);
+                        self.apply_metadata(&abs_path, metadata.as_ref());
+                        self.mark_as_modified(&path);
+
+                        // Other clients get the file from us, the same way they'd get our own changes
+                        let metadata = self.local_metadata(&abs_path);
+                        forward = Some(Outgoing::File { path, abs_path, delta: existed, metadata });
+                    }
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+            MessageType::SignatureRequest { path } => {
+                return vec![Outgoing::Message(self.make_signatures(path))];
+            }
+            MessageType::Signatures { path, base_hash, block_size, blocks } => {
+                let abs_path = make_absolute_path(path);
+                if !self.files.contains(&abs_path) {
+                    eprintln!("Received signatures for {}, which no longer exists", path);
+                    return vec![];
+                }
+
+                // No usable copy on the other side, or the file is too large to diff
+                let whole = Outgoing::File {
+                    path: path.clone(),
+                    abs_path: abs_path.clone(),
+                    delta: false,
+                    metadata: self.local_metadata(&abs_path),
+                };
+                if blocks.is_empty() || FileWatcher::file_size(&abs_path) > DELTA_MAX_SIZE {
+                    return vec![whole];
+                }
+
+                // The writer works out the delta, so nothing is read while we hold on to the index
+                return vec![Outgoing::Delta {
+                    path: path.clone(),
+                    abs_path,
+                    base_hash: *base_hash,
+                    block_size: *block_size,
+                    blocks: blocks.clone(),
+                    metadata: self.local_metadata(&make_absolute_path(path)),
+                }];
+            }
+            MessageType::DeltaEvent { path, base_hash, block_size, ops, size, hash, metadata, parent_hash } => {
+                let abs_path = make_absolute_path(path);
+                let base = std::fs::File::open(&abs_path);
+                if base.is_err() || hash_file(&abs_path).ok() != Some(*base_hash) {
+                    // Our copy changed since we sent the signatures, ask for the whole file instead
+                    eprintln!("Delta for {} doesn't match our copy, requesting it whole", path);
+                    return vec![Outgoing::Message(MessageType::Signatures {
+                        path: path.clone(),
+                        base_hash: 0,
+                        block_size: 0,
+                        blocks: vec![],
+                    })];
+                }
+
+                // The result goes into a temp file like a streamed one, neither copy is read into memory
+                let mut base = base.unwrap();
+                let base_len = base.metadata().map(|m| m.len()).unwrap_or(0);
+                let transfer = IncomingTransfer::begin(path, &abs_path, next_transfer_id(), *size, *metadata, *parent_hash);
+                if let Err(e) = transfer {
+                    eprintln!("Failed to start applying delta for {}: {:?}", path, e);
+                    return vec![];
+                }
+
+                let mut transfer = transfer.unwrap();
+                if let Err(e) = apply_delta(&mut base, base_len, *block_size, ops, *size, &mut transfer) {
+                    eprintln!("Failed to apply delta for {}: {}", path, e);
+                    transfer.abort();
+                    return vec![];
+                }
+
+                if let Some(replies) = self.resolve_conflict(&abs_path, *parent_hash, *hash, is_authorative) {
+                    transfer.abort();
+                    return replies;
+                }
+
+                self.keep_previous(path, Some(*hash));
+                if let Err(e) = transfer.finish(&abs_path, *hash) {
+                    eprintln!("Failed to apply delta: {}", e);
+                    return vec![];
+                }
+                self.remember_file(&abs_path, *hash);
+                self.confirm(peer, path, *hash);
+                self.apply_metadata(&abs_path, metadata.as_ref());
+                self.mark_as_modified(path);
+
+                // The delta only applies to the sender's base, other clients get their own
+                let metadata = self.local_metadata(&abs_path);
+                forward = Some(Outgoing::File { path: path.clone(), abs_path, delta: true, metadata });
+            }
+            MessageType::MetadataEvent { path, metadata } => {
+                let abs_path = make_absolute_path(path);
+                if !self.files.contains(&abs_path) {
+                    eprintln!("Received metadata for unknown file {}", path);
+                    return vec![];
                 }
-                self.mark_as_modified(&old_path);
-                self.mark_as_modified(&new_path);
+
+                self.apply_metadata(&abs_path, Some(metadata));
+                self.mark_as_modified(path);
+                forward = Some(Outgoing::Message(msg.clone()));
+            }
+            MessageType::SymlinkEvent { path, target } => {
+                if self.write_symlink(path, target) {
+                    forward = Some(Outgoing::Message(msg.clone()));
+                }
+            }
+        }
+
+        if self.is_server && let Some(forward) = forward {
+            self.forwards.push(forward);
+        }
+        vec![]
+    }
+
+    // The server passes on changes to its other clients, and has no journal.
+    pub fn set_is_server(&mut self) {
+        self.is_server = true;
+    }
+
+    pub fn authority(&self) -> Authority {
+        self.options.authority
+    }
+
+    pub fn direction(&self) -> Direction {
+        self.options.direction
+    }
+
+    pub fn capabilities(&self) -> Vec<String> {
+        protocol::local_capabilities(self.options.preserve_metadata)
+    }
+
+    pub fn set_local_changes(&mut self, local_changes: LocalChanges) {
+        self.local_changes = local_changes;
+    }
+
+    pub fn set_extra_files(&mut self, extra_files: ExtraFiles) {
+        self.extra_files = extra_files;
+    }
+
+    // Whether the last manifest was refused, see `Brake::refuse_empty`
+    pub fn take_refused(&mut self) -> bool {
+        std::mem::take(&mut self.refused)
+    }
+
+    // Whether syncing with an empty tree would delete what we have here. A client that was in
+    // sync with files before can't have expected the server to be empty either.
+    fn would_lose_files(&self) -> bool {
+        let was_synced = self.journal.as_ref().is_some_and(|journal| !journal.synced.is_empty());
+        was_synced
+            || self.options.direction == Direction::Mirror
+            || self.local_changes == LocalChanges::Revert
+            || self.extra_files == ExtraFiles::Delete
+    }
+
+    // Goes ahead with the deletes the brake was holding, once they're confirmed. Returns the
+    // ones to send: the ones made here, and on the server also the ones from a client, for the
+    // other clients. See `brake.rs`.
+    pub fn take_released(&mut self) -> Vec<Outgoing> {
+        let Some(released) = self.brake.confirmed() else {
+            return vec![];
+        };
+
+        let mut send = Vec::new();
+        for held in released {
+            if held.incoming && (!self.apply_delete(&held.path, held.dir) || !self.is_server) {
+                continue;
+            }
+
+            let path = held.path;
+            send.push(Outgoing::Message(if held.dir { MessageType::DeleteDir { path, parent_hashes: None } } else { MessageType::DeleteEvent { path, parent_hash: None } }));
+        }
+        send
+    }
+
+    fn apply_delete(&mut self, path: &str, dir: bool) -> bool {
+        self.keep_previous(path, None);
+        let abs_path = self.absolute_path(path);
+        let result = if dir { std::fs::remove_dir_all(&abs_path) } else { std::fs::remove_file(&abs_path) };
+        self.forget_path(Path::new(&abs_path));
+        self.mark_as_modified(path);
+        if let Err(e) = result {
+            eprintln!("Failed to delete {} {}: {:?}", if dir { "directory" } else { "file" }, path, e);
+            return false;
+        }
+        true
+    }
+
+    // Copies what's at `path` into the trash before it's overwritten with contents that hash to
+    // `new_hash`, or deleted. For a directory, that's every file inside of it.
+    fn keep_previous(&self, path: &str, new_hash: Option<u64>) {
+        let Some(trash) = &self.trash else {
+            return;
+        };
+        let abs_path = self.absolute_path(path);
+        if new_hash.is_some() && self.file_hashes.get(&abs_path).copied() == new_hash {
+            return;
+        }
+
+        let prefix = format!("{}/", abs_path);
+        for file in self.files.iter().filter(|file| **file == abs_path || file.starts_with(&prefix)) {
+            trash.keep(&self.relative_path(file), Path::new(file));
+        }
+    }
+
+    // How many files a delete of `path` takes with it
+    fn files_under(&self, path: &str) -> usize {
+        let abs_path = self.absolute_path(path);
+        let prefix = format!("{}/", abs_path);
+        self.files.iter().filter(|file| **file == abs_path || file.starts_with(&prefix)).count().max(1)
+    }
+
+    // Called when a client goes away, the transfers it had under way won't be finished.
+    pub fn abort_transfers(&mut self, ids: &HashSet<u64>) {
+        for id in ids {
+            if let Some(transfer) = self.transfers.remove(id) {
+                eprintln!("Aborting transfer of {}, its sender is gone", transfer.path);
+                transfer.abort();
             }
         }
     }
 
+    // Reads the journal left behind by the last time we were connected, see `journal.rs`.
+    pub fn load_journal(&mut self) {
+        self.journal = Journal::load(&self.root);
+        self.journal_current = false;
+    }
+
+    // Called when we lose the connection to the server. Transfers that were under way won't be
+    // finished, and the journal stops following along until we've reconciled again.
+    pub fn disconnected(&mut self) {
+        for (_, transfer) in self.transfers.drain() {
+            transfer.abort();
+        }
+        self.update_journal();
+        self.journal_current = false;
+    }
+
+    // Records what we have now, and saves the journal if anything changed. Only while we're
+    // connected, whatever happens after that is what the next reconcile needs to find.
+    pub fn update_journal(&mut self) {
+        if !self.journal_current || self.journal.is_none() {
+            return;
+        }
+
+        let sent: HashMap<String, u64> = self
+            .file_hashes
+            .iter()
+            .map(|(path, hash)| (self.relative_path(path), *hash))
+            .collect();
+
+        let journal = self.journal.as_mut().unwrap();
+        if journal.sent == sent && !self.journal_changed {
+            return;
+        }
+
+        journal.sent = sent;
+        if let Err(e) = journal.save(&self.root) {
+            eprintln!("Failed to save journal in '{}': {:?}", self.root, e);
+        }
+        self.journal_changed = false;
+    }
+
+    // A change from the other side made it to disk, so the peer has this version too.
+    fn confirm(&mut self, peer: &mut PeerHashes, path: &str, hash: u64) {
+        peer.set(path, hash);
+        if let Some(journal) = &mut self.journal {
+            journal.synced.set(path, hash);
+            self.journal_changed = true;
+        }
+    }
+
+    fn confirm_removed(&mut self, peer: &mut PeerHashes, path: &str) {
+        peer.remove(path);
+        if let Some(journal) = &mut self.journal {
+            journal.synced.remove(path);
+            self.journal_changed = true;
+        }
+    }
+
+    fn confirm_renamed(&mut self, peer: &mut PeerHashes, from: &str, to: &str) {
+        peer.rename(from, to);
+        if let Some(journal) = &mut self.journal {
+            journal.synced.rename(from, to);
+            self.journal_changed = true;
+        }
+    }
+
+    // Changes applied since the last call, for the server to pass on to everyone but their sender.
+    pub fn take_forwards(&mut self) -> Vec<Outgoing> {
+        std::mem::take(&mut self.forwards)
+    }
+
+    // Creates a symlink the other side told us about, unless it would point outside of the tree.
+    fn write_symlink(&mut self, path: &str, target: &str) -> bool {
+        if self.options.symlinks == SymlinkMode::Skip {
+            eprintln!("Skipping symlink {}, symlinks are disabled", path);
+            return false;
+        }
+
+        let abs_path = self.absolute_path(path);
+        if !symlink::stays_inside(Path::new(&self.root), Path::new(&abs_path), target) {
+            eprintln!("Refusing to create symlink {} -> {}, it points outside of '{}'", path, target, self.root);
+            return false;
+        }
+
+        create_parent_dir(&abs_path);
+        if let Err(e) = symlink::create(Path::new(&abs_path), target) {
+            eprintln!("Failed to create symlink {}: {:?}", path, e);
+            return false;
+        }
+
+        self.forget_path(Path::new(&abs_path));
+        self.symlinks.insert(abs_path, target.to_string());
+        self.mark_as_modified(path);
+        true
+    }
+
+    // Describes our copy of a file, so the other side can send us only what changed. The file is
+    // read a block at a time, and not at all past the size the other side would diff.
+    fn make_signatures(&self, path: &str) -> MessageType {
+        let abs_path = self.absolute_path(path);
+        let size = FileWatcher::file_size(&abs_path);
+        let signatures = if self.files.contains(&abs_path) && size > 0 && size <= DELTA_MAX_SIZE {
+            std::fs::File::open(&abs_path).and_then(|mut file| signatures(&mut file, size)).map_err(|e| {
+                eprintln!("Failed to read {} for signatures: {:?}", path, e);
+            })
+        } else {
+            Err(())
+        };
+
+        match signatures {
+            Ok((block_size, blocks, base_hash)) => MessageType::Signatures {
+                path: path.to_string(),
+                base_hash,
+                block_size,
+                blocks,
+            },
+            Err(()) => MessageType::Signatures {
+                path: path.to_string(),
+                base_hash: 0,
+                block_size: 0,
+                blocks: vec![],
+            },
+        }
+    }
+
     pub fn make_message_type(&self, event: &notify::Event) -> Option<MessageType> {
         let make_local_path = |path: &str| -> String {
             let path = path.strip_prefix(&self.root)
@@ -124,6 +818,23 @@ impl FileWatcher {
         };
 
         match event.kind {
+            notify::EventKind::Create(notify::event::CreateKind::Folder) => {
+                let path = make_local_path(&event.paths[0].to_string_lossy());
+                Some(MessageType::CreateDir { path })
+            }
+
+            notify::EventKind::Remove(notify::event::RemoveKind::Folder) => {
+                let path = make_local_path(&event.paths[0].to_string_lossy());
+                Some(MessageType::DeleteDir { path, parent_hashes: None })
+            }
+
+            // Set by `try_get_event` for symlinks we preserve
+            notify::EventKind::Create(notify::event::CreateKind::Other) => {
+                let target = self.symlinks.get(event.paths[0].to_str()?)?.clone();
+                let path = make_local_path(&event.paths[0].to_string_lossy());
+                Some(MessageType::SymlinkEvent { path, target })
+            }
+
             notify::EventKind::Create(_) => {
                 assert!(event.paths.len() == 1, "More than one path in event");
                 let path = event.paths[0].to_str();
@@ -133,9 +844,24 @@ impl FileWatcher {
                 }
 
                 let path = path.unwrap();
-                let contents = std::fs::read(&path).unwrap();
+                let contents = std::fs::read(path).unwrap();
+                let metadata = self.local_metadata(path);
                 let path = make_local_path(path);
-                Some(MessageType::CreateEvent { path, contents })
+                Some(MessageType::CreateEvent { path, contents, metadata, parent_hash: None })
+            }
+
+            notify::EventKind::Modify(notify::event::ModifyKind::Name(_)) => {
+                assert!(event.paths.len() == 2, "Rename event without both paths");
+                let old_path = make_local_path(&event.paths[0].to_string_lossy());
+                let new_path = make_local_path(&event.paths[1].to_string_lossy());
+                Some(MessageType::MoveEvent { old_path, new_path, parent_hash: None })
+            }
+
+            notify::EventKind::Modify(notify::event::ModifyKind::Metadata(_)) => {
+                let path = event.paths[0].to_string_lossy().to_string();
+                let metadata = self.local_metadata(&path)?;
+                let path = make_local_path(&path);
+                Some(MessageType::MetadataEvent { path, metadata })
             }
 
             notify::EventKind::Modify(_) => {
@@ -147,9 +873,10 @@ impl FileWatcher {
                 }
 
                 let path = path.unwrap();
-                let contents = std::fs::read(&path).unwrap();
+                let contents = std::fs::read(path).unwrap();
+                let metadata = self.local_metadata(path);
                 let path = make_local_path(path);
-                Some(MessageType::ModifyEvent { path, contents })
+                Some(MessageType::ModifyEvent { path, contents, metadata, parent_hash: None })
             }
 
             notify::EventKind::Remove(_) => {
@@ -161,26 +888,66 @@ impl FileWatcher {
                 }
 
                 let path = make_local_path(path.unwrap());
-                Some(MessageType::DeleteEvent { path })
+                Some(MessageType::DeleteEvent { path, parent_hash: None })
             }
 
             _ => None,
         }
     }
 
-    pub fn make_msg_data(&self, event: &notify::Event) -> Option<Vec<u8>> {
-        let msg = self.make_message_type(event);
-        if msg.is_none() {
-            return None;
+    pub fn make_outgoing(&mut self, event: &notify::Event) -> Option<Outgoing> {
+        let outgoing = self.make_unbraked_outgoing(event)?;
+        let Outgoing::Message(msg) = &outgoing else {
+            return Some(outgoing);
+        };
+
+        // The index already forgot about whatever was deleted, so every delete counts as one file.
+        // `rm -rf` deletes the files inside of a directory one by one anyway.
+        match msg {
+            MessageType::DeleteEvent { path, .. } | MessageType::DeleteDir { path, .. } => {
+                if self.brake.allow(1, self.files.len()) {
+                    return Some(outgoing);
+                }
+                let dir = matches!(msg, MessageType::DeleteDir { .. });
+                self.brake.hold(HeldDelete { path: path.clone(), dir, incoming: false });
+                None
+            }
+            msg => {
+                if self.brake.is_holding() {
+                    for (path, _) in paths::paths(msg) {
+                        self.brake.cancel(path);
+                    }
+                }
+                Some(outgoing)
+            }
+        }
+    }
+
+    fn make_unbraked_outgoing(&self, event: &notify::Event) -> Option<Outgoing> {
+        // Don't read large files here, let the writer stream them or send a delta instead
+        if let notify::EventKind::Create(notify::event::CreateKind::File) | notify::EventKind::Modify(notify::event::ModifyKind::Data(_)) = event.kind
+            && let Some(path) = event.paths.first().and_then(|p| p.to_str())
+        {
+            let is_modify = matches!(event.kind, notify::EventKind::Modify(_));
+            let size = FileWatcher::file_size(path);
+            if size > CHUNKED_THRESHOLD || (is_modify && size > DELTA_THRESHOLD) {
+                return Some(Outgoing::File {
+                    path: self.relative_path(path),
+                    abs_path: path.to_string(),
+                    delta: is_modify,
+                    metadata: self.local_metadata(path),
+                });
+            }
         }
 
-        Some(compose_data_message(&msg.unwrap()))
+        let msg = self.make_message_type(event)?;
+        Some(Outgoing::Message(msg))
     }
 
     pub fn try_get_event(&mut self) -> Result<notify::Event, RecvTimeoutError> {
         let mut i: i64 = 3;
         loop {
-            let receive_result = self.events.recv_timeout(std::time::Duration::from_millis(
+            let receive_result = self.next_event(std::time::Duration::from_millis(
                 (i * 10).clamp(1, 30).try_into().unwrap(),
             ));
             if receive_result.is_err() {
@@ -193,32 +960,109 @@ impl FileWatcher {
             let start = std::time::Instant::now();
             match event {
                 Ok(event) => {
+                    let event = self.resolve_rename(event);
+                    if event.is_none() {
+                        i -= 1;
+                        continue;
+                    }
+
+                    let mut event = event.unwrap();
+
+                    // Nothing hidden is synced, which includes our own `.remote-fs` folder
+                    if let notify::EventKind::Create(_) = event.kind
+                        && self.is_hidden(&event.paths[0])
+                    {
+                        i -= 1;
+                        continue;
+                    }
+
                     let mut allow_change = match event.kind {
                         notify::EventKind::Create(_) => {
                             self.index_files(); // Re-index files
-                            self.has_file(&event)
+
+                            // Not every backend tells us what was created
+                            if self.preserves_symlink(&event.paths[0]) {
+                                event.kind = notify::EventKind::Create(notify::event::CreateKind::Other);
+                                let path = event.paths[0].to_string_lossy().to_string();
+                                let has_symlink = self.symlinks.contains_key(&path);
+                                if !has_symlink {
+                                    eprintln!("Not syncing symlink {}, it points outside of '{}'", path, self.root);
+                                }
+                                has_symlink
+                            } else if event.paths[0].is_dir() {
+                                event.kind = notify::EventKind::Create(notify::event::CreateKind::Folder);
+                                let has_dir = self.has_dir(&event.paths[0]);
+                                if has_dir {
+                                    // Anything inside may have been created before we started watching it
+                                    self.queue_dir_contents(&event.paths[0]);
+                                }
+                                has_dir
+                            } else {
+                                event.kind = notify::EventKind::Create(notify::event::CreateKind::File);
+                                self.has_file(&event)
+                            }
                         }
 
                         notify::EventKind::Modify(e) => match e {
+                            notify::event::ModifyKind::Metadata(_) => {
+                                let path = event.paths[0].to_str().unwrap();
+                                let metadata = if self.has_file(&event) { self.local_metadata(path) } else { None };
+                                match metadata {
+                                    Some(metadata) if self.file_metadata.get(path) != Some(&metadata) => {
+                                        self.file_metadata.insert(path.to_string(), metadata);
+                                        true
+                                    }
+                                    _ => false,
+                                }
+                            }
                             notify::event::ModifyKind::Any
-                            | notify::event::ModifyKind::Metadata(_)
                             | notify::event::ModifyKind::Other => {
                                 // eprintln!("Rejecting new event, ModifyKind is undesired ({:?})", e);
                                 false
                             }
-                            notify::event::ModifyKind::Data(_)
-                            | notify::event::ModifyKind::Name(_) => {
-                                let has_changes = self.has_changes_read(event.paths[0].to_str().unwrap());
-                                if has_changes {
-                                    // self.index_files(); // Re-index files
-                                    self.has_file(&event)
-                                } else {
-                                    false
+                            notify::event::ModifyKind::Name(_) => {
+                                // Only complete renames get this far, see `resolve_rename`
+                                self.rename_in_index(&event.paths[0], &event.paths[1]);
+                                true
+                            }
+                            notify::event::ModifyKind::Data(_) => {
+                                // The file may already be gone again
+                                let path = event.paths[0].to_str().unwrap();
+                                let hash = if self.has_file(&event) { hash_file(path).ok() } else { None };
+                                match hash {
+                                    Some(hash) if self.has_changes(path, hash) => {
+                                        // Remember what we've sent, so the manifest stays accurate
+                                        self.remember_file(path, hash);
+                                        true
+                                    }
+                                    _ => false,
                                 }
                             }
                         },
 
-                        notify::EventKind::Remove(_) => self.has_file(&event),
+                        // Already back, e.g. written by a change from the other side before we got to
+                        // this event. Deleting it over there would take that change with it.
+                        notify::EventKind::Remove(_) if std::fs::symlink_metadata(&event.paths[0]).is_ok() => false,
+
+                        notify::EventKind::Remove(_) => {
+                            // It's gone, so the index is the only way to know whether it was a directory
+                            let path = event.paths[0].clone();
+                            if self.has_dir(&path) {
+                                event.kind = notify::EventKind::Remove(notify::event::RemoveKind::Folder);
+                                self.forget_path(&path);
+                                true
+                            } else if self.symlinks.contains_key(&*path.to_string_lossy()) {
+                                event.kind = notify::EventKind::Remove(notify::event::RemoveKind::File);
+                                self.forget_path(&path);
+                                true
+                            } else {
+                                let has_file = self.has_file(&event);
+                                if has_file {
+                                    self.forget_path(&path);
+                                }
+                                has_file
+                            }
+                        }
 
                         notify::EventKind::Any
                         | notify::EventKind::Access(_)
@@ -253,7 +1097,7 @@ impl FileWatcher {
                     eprintln!(
                         "Filesystem {} file '{:?}', took {}ms",
                         event_str,
-                        event.paths.get(0).unwrap(),
+                        event.paths.first().unwrap(),
                         elapsed.as_millis()
                     );
                     return Ok(event);
@@ -267,47 +1111,867 @@ impl FileWatcher {
         }
     }
 
-    fn has_file(&self, event: &notify::Event) -> bool {
-        if let Some(path) = event.paths.get(0) {
-            let path_str = path.to_str().unwrap();
-            // let path_str = path_str.strip_prefix(&self.root).unwrap_or(path_str);
-            // let path_str = path_str.strip_prefix("/").unwrap_or(path_str);
-            self.files.iter().any(|f| f == path_str)
-        } else {
-            eprintln!("Error: No path in event"); // ??
-            false
-        }
+    fn next_event(&mut self, timeout: std::time::Duration) -> Result<Result<notify::Event, notify::Error>, RecvTimeoutError> {
+        loop {
+            self.expire_renames();
+            let event = match self.queued_events.pop_front() {
+                Some(event) => event,
+                None => self.events.recv_timeout(timeout)?,
+            };
+
+            // Writing a large file produces a burst of identical modify events. Each one means
+            // hashing the whole file, so skip ahead to the last one that has arrived so far.
+            if let Ok(e) = &event
+                && let notify::EventKind::Modify(notify::event::ModifyKind::Data(_)) = e.kind
+            {
+                while let Ok(next) = self.events.try_recv() {
+                    self.queued_events.push_back(next);
+                }
+
+                let superseded = self
+                    .queued_events
+                    .iter()
+                    .any(|q| matches!(q, Ok(q) if q.kind == e.kind && q.paths == e.paths));
+                if superseded {
+                    continue;
+                }
+            }
+
+            return Ok(event);
+        }
+    }
+
+    // Pairs up the two halves of a rename (matched by their tracker cookie) into a single
+    // `RenameMode::Both` event. Returns None while we're still waiting for the other half,
+    // or when the event turned out to be a duplicate.
+    fn resolve_rename(&mut self, event: notify::Event) -> Option<notify::Event> {
+        use notify::event::{ModifyKind, RenameMode};
+
+        let notify::EventKind::Modify(ModifyKind::Name(mode)) = event.kind else {
+            return Some(event);
+        };
+
+        let tracker = event.attrs.tracker();
+        match mode {
+            RenameMode::From => {
+                if let (Some(tracker), Some(path)) = (tracker, event.paths.first()) {
+                    self.pending_renames.insert(tracker, (path.clone(), std::time::Instant::now()));
+                }
+                None
+            }
+
+            RenameMode::To => {
+                let to = event.paths.first()?.clone();
+                let from = tracker.and_then(|t| self.pending_renames.remove(&t));
+                match from {
+                    Some((from, _)) => {
+                        // Some backends follow up with a `Both` event, which we've now already handled
+                        self.completed_renames.insert(tracker.unwrap());
+                        Some(self.make_rename_event(from, to))
+                    }
+
+                    // Moved in from outside of the tree
+                    None => Some(self.make_write_event(to)),
+                }
+            }
+
+            RenameMode::Both => {
+                if let Some(tracker) = tracker {
+                    if self.completed_renames.remove(&tracker) {
+                        return None;
+                    }
+                    self.pending_renames.remove(&tracker);
+                }
+
+                if event.paths.len() != 2 {
+                    eprintln!("Error: Rename event without both paths");
+                    return None;
+                }
+                Some(self.make_rename_event(event.paths[0].clone(), event.paths[1].clone()))
+            }
+
+            // Backends that can't tell us which side of the rename this is
+            RenameMode::Any | RenameMode::Other => {
+                let path = event.paths.first()?.clone();
+                if path.exists() {
+                    Some(self.make_write_event(path))
+                } else {
+                    Some(notify::Event::new(notify::EventKind::Remove(notify::event::RemoveKind::File)).add_path(path))
+                }
+            }
+        }
+    }
+
+    fn make_rename_event(&self, from: PathBuf, to: PathBuf) -> notify::Event {
+        let from_str = from.to_string_lossy();
+
+        // Renaming something we don't track (like a temp file) over a tracked file is just a write
+        if !self.files.contains(&*from_str)
+            && !self.has_dir(&from)
+            && !self.symlinks.contains_key(&*from_str)
+        {
+            return self.make_write_event(to);
+        }
+
+        // Renaming a tracked file to something we don't track, is as good as deleting it
+        if self.is_hidden(&to) {
+            return notify::Event::new(notify::EventKind::Remove(notify::event::RemoveKind::File)).add_path(from);
+        }
+
+        notify::Event::new(notify::EventKind::Modify(notify::event::ModifyKind::Name(
+            notify::event::RenameMode::Both,
+        )))
+        .add_path(from)
+        .add_path(to)
+    }
+
+    fn make_write_event(&self, path: PathBuf) -> notify::Event {
+        let path_str = path.to_string_lossy();
+        let kind = if self.preserves_symlink(&path) {
+            // Replacing a link is as good as creating it, `try_get_event` sorts out the details
+            notify::EventKind::Create(notify::event::CreateKind::File)
+        } else if path.is_dir() {
+            notify::EventKind::Create(notify::event::CreateKind::Folder)
+        } else if self.files.contains(&*path_str) {
+            notify::EventKind::Modify(notify::event::ModifyKind::Data(notify::event::DataChange::Any))
+        } else {
+            notify::EventKind::Create(notify::event::CreateKind::File)
+        };
+        notify::Event::new(kind).add_path(path)
+    }
+
+    // A rename whose other half never showed up was moved out of the tree.
+    fn expire_renames(&mut self) {
+        let expired: Vec<usize> = self
+            .pending_renames
+            .iter()
+            .filter(|(_, (_, since))| since.elapsed() > RENAME_TIMEOUT)
+            .map(|(tracker, _)| *tracker)
+            .collect();
+
+        for tracker in expired {
+            let (path, _) = self.pending_renames.remove(&tracker).unwrap();
+            let event = notify::Event::new(notify::EventKind::Remove(notify::event::RemoveKind::File)).add_path(path);
+            self.queued_events.push_back(Ok(event));
+        }
+    }
+
+    // Files the walker in `index_files` would skip
+    fn is_hidden(&self, path: &Path) -> bool {
+        let relative_path = path.strip_prefix(&self.root).unwrap_or(path);
+        relative_path
+            .components()
+            .any(|c| c.as_os_str().to_string_lossy().starts_with('.'))
+    }
+
+    fn queue_dir_contents(&mut self, dir: &Path) {
+        for entry in self.walk(dir).filter_map(|entry| entry.ok()).filter(|entry| entry.depth() > 0) {
+            let kind = if entry.path().is_dir() {
+                notify::EventKind::Create(notify::event::CreateKind::Folder)
+            } else {
+                notify::EventKind::Create(notify::event::CreateKind::File)
+            };
+            let event = notify::Event::new(kind).add_path(entry.path().to_path_buf());
+            self.queued_events.push_back(Ok(event));
+        }
+    }
+
+    // Walks a directory, following symlinks only if we're configured to, and only those that stay inside the root.
+    fn walk(&self, dir: &Path) -> Walk {
+        let follow = self.options.symlinks == SymlinkMode::Follow;
+        let root = PathBuf::from(&self.root);
+        WalkBuilder::new(dir)
+            .follow_links(follow)
+            .filter_entry(move |entry| !follow || !entry.path_is_symlink() || symlink::resolves_inside(&root, entry.path()))
+            .build()
+    }
+
+    fn preserves_symlink(&self, path: &Path) -> bool {
+        self.options.symlinks == SymlinkMode::Preserve && symlink::is_symlink(path)
+    }
+
+    fn has_dir(&self, path: &Path) -> bool {
+        self.dirs.contains(&*path.to_string_lossy())
+    }
+
+    fn has_file(&self, event: &notify::Event) -> bool {
+        if let Some(path) = event.paths.first() {
+            let path_str = path.to_str().unwrap();
+            // let path_str = path_str.strip_prefix(&self.root).unwrap_or(path_str);
+            // let path_str = path_str.strip_prefix("/").unwrap_or(path_str);
+            self.files.contains(path_str)
+        } else {
+            eprintln!("Error: No path in event"); // ??
+            false
+        }
     }
 
-    pub fn get_relative_files(&self) -> HashMap<String, Vec<u8>> {
-        self.files
+    pub fn get_manifest(&self) -> MessageType {
+        let entries = self.files
             .iter()
-            .map(|file| {
-                let path = Path::new(&file);
-                let relative_path = path.strip_prefix(&self.root).unwrap_or(path);
-                let relative_path = relative_path.strip_prefix("/").unwrap_or(relative_path);
-                (
-                    relative_path.to_string_lossy().to_string(),
-                    std::fs::read(file).expect("Could not read file"),
-                )
+            .filter_map(|file| {
+                let metadata = std::fs::metadata(file);
+                if let Err(e) = metadata {
+                    eprintln!("Failed to read metadata of {}: {:?}", file, e);
+                    return None;
+                }
+
+                let metadata = metadata.unwrap();
+                let file_metadata = FileMetadata::from(&metadata);
+                Some(ManifestEntry {
+                    path: self.relative_path(file),
+                    size: metadata.len(),
+                    mtime: file_metadata.mtime,
+                    hash: *self.file_hashes.get(file).unwrap_or(&0),
+                    mode: file_metadata.mode,
+                })
+            })
+            .collect();
+
+        let dirs = self.dirs.iter().map(|dir| self.relative_path(dir)).collect();
+        let symlinks = self
+            .symlinks
+            .iter()
+            .map(|(path, target)| (self.relative_path(path), target.clone()))
+            .collect();
+        MessageType::Manifest { entries, dirs, symlinks }
+    }
+
+    // Returns the paths from a manifest that we either don't have, or have different contents for.
+    fn get_outdated_files(&self, entries: &[ManifestEntry]) -> Vec<String> {
+        entries
+            .iter()
+            .filter(|entry| {
+                let path = self.absolute_path(&entry.path);
+                self.file_hashes.get(&path) != Some(&entry.hash)
             })
-            .collect::<HashMap<String, Vec<u8>>>()
+            .map(|entry| entry.path.clone())
+            .collect()
+    }
+
+    // Works out what changed on either side since we were last connected, by comparing our files
+    // and the server's manifest with the journal. Returns the files to fetch, and what to send.
+    // Files that changed on both sides are fetched, after keeping our copy next to them.
+    fn reconcile(&mut self, entries: &[ManifestEntry], mut journal: Journal) -> (Vec<String>, Vec<Outgoing>) {
+        let mut fetch = Vec::new();
+        let mut send = Vec::new();
+        let mut revert = Vec::new();
+        let mut extras = Vec::new();
+        let mut synced = journal.synced.clone();
+        for entry in entries {
+            let abs_path = self.absolute_path(&entry.path);
+            let local = self.file_hashes.get(&abs_path).copied();
+            let unchanged_on_server = journal.knows(&entry.path, entry.hash);
+            match local {
+                Some(local) if local == entry.hash => {}
+
+                // A read-only client doesn't tell the server about its changes, and either leaves
+                // them be or gets the server's copy back
+                _ if unchanged_on_server && self.local_changes == LocalChanges::Keep => {}
+                _ if self.local_changes == LocalChanges::Revert => {
+                    eprintln!("{} differs from the server's copy, fetching it", entry.path);
+                    fetch.push(entry.path.clone());
+                    continue;
+                }
+
+                None if unchanged_on_server => {
+                    eprintln!("{} was deleted while disconnected, deleting it on the server", entry.path);
+                    if self.brake.allow(1, self.files.len()) {
+                        send.push(Outgoing::Message(MessageType::DeleteEvent { path: entry.path.clone(), parent_hash: None }));
+                    } else {
+                        self.brake.hold(HeldDelete { path: entry.path.clone(), dir: false, incoming: false });
+                    }
+                }
+                Some(_) if unchanged_on_server => {
+                    eprintln!("{} was changed while disconnected, sending it to the server", entry.path);
+                    send.push(self.make_file_outgoing(&abs_path));
+                }
+
+                // Only changed on the server, what's in the journal stays until we've received it
+                None => {
+                    fetch.push(entry.path.clone());
+                    continue;
+                }
+                Some(local) if journal.synced.get(&entry.path) == Some(local) => {
+                    fetch.push(entry.path.clone());
+                    continue;
+                }
+
+                Some(_) => {
+                    let copy = conflict_path(Path::new(&abs_path));
+                    if let Err(e) = std::fs::copy(&abs_path, &copy) {
+                        eprintln!("Failed to keep conflicting copy of {}, not fetching it: {:?}", entry.path, e);
+                        continue;
+                    }
+
+                    eprintln!(
+                        "{} was changed here and on the server while disconnected, keeping our copy as {}",
+                        entry.path,
+                        copy.display()
+                    );
+                    fetch.push(entry.path.clone());
+                    continue;
+                }
+            }
+            synced.set(&entry.path, entry.hash);
+        }
+
+        // Files the server doesn't have at all
+        let on_server: HashSet<&str> = entries.iter().map(|entry| entry.path.as_str()).collect();
+        for (file, hash) in &self.file_hashes {
+            let path = self.relative_path(file);
+            if on_server.contains(path.as_str()) {
+                continue;
+            }
+
+            // Deleted on the server while we were away, or left alone the last time
+            let known = journal.synced.get(&path) == Some(*hash) || journal.extra.get(&path) == Some(hash);
+            if known && self.local_changes != LocalChanges::Revert {
+                extras.push(file.clone());
+                continue;
+            }
+
+            match self.local_changes {
+                LocalChanges::Send => {}
+                LocalChanges::Keep => continue,
+                LocalChanges::Revert => {
+                    revert.push(file.clone());
+                    continue;
+                }
+            }
+
+            let change = if journal.knows(&path, *hash) { "changed" } else { "created" };
+            eprintln!("{} was {} while disconnected, sending it to the server", path, change);
+            send.push(self.make_file_outgoing(file));
+        }
+
+        for file in revert {
+            self.revert_local_file(&file);
+        }
+
+        journal.synced = synced;
+        journal.extra.clear();
+        self.journal = Some(journal);
+        send.extend(self.handle_extra_files(extras));
+        (fetch, send)
+    }
+
+    // Deletes, sends or just logs the files we have that the server doesn't, see `ExtraFiles`.
+    // Returns what to send.
+    fn handle_extra_files(&mut self, mut extras: Vec<String>) -> Vec<Outgoing> {
+        extras.sort();
+        let mut send = Vec::new();
+        for file in extras {
+            let path = self.relative_path(&file);
+            match self.extra_files {
+                ExtraFiles::Report => {
+                    eprintln!("{} isn't on the server, leaving it", path);
+                    self.keep_extra(&file);
+                }
+                ExtraFiles::Delete => {
+                    self.remove_local(&file, "the server");
+                }
+                ExtraFiles::Upload if self.local_changes == LocalChanges::Send => {
+                    eprintln!("{} isn't on the server, sending it", path);
+                    send.push(self.make_file_outgoing(&file));
+                }
+                ExtraFiles::Upload => {
+                    eprintln!("{} isn't on the server, leaving it as we don't send changes", path);
+                    self.keep_extra(&file);
+                }
+            }
+        }
+        send
+    }
+
+    fn keep_extra(&mut self, abs_path: &str) {
+        let path = self.relative_path(abs_path);
+        if let (Some(journal), Some(hash)) = (&mut self.journal, self.file_hashes.get(abs_path)) {
+            journal.extra.insert(path, *hash);
+        }
+    }
+
+    // Undoes a local change, by asking the server for its copy of whatever the event touched,
+    // or deleting it if the server doesn't have it. Only while we're connected, the next
+    // reconcile takes care of what happens in the meantime.
+    pub fn revert(&mut self, event: &notify::Event) -> Option<Outgoing> {
+        if !self.journal_current || self.journal.is_none() {
+            return None;
+        }
+
+        let mut fetch = Vec::new();
+        for abs_path in event.paths.iter().filter_map(|p| p.to_str()) {
+            let path = self.relative_path(abs_path);
+            let synced = &self.journal.as_ref().unwrap().synced;
+
+            // A file, or a directory with files in it, that the server has
+            let mut server_files = synced.paths_under(&path);
+            if synced.get(&path).is_some() {
+                server_files.push(path.clone());
+            }
+
+            if server_files.is_empty() {
+                self.revert_local_file(abs_path);
+                continue;
+            }
+
+            for path in server_files {
+                let hash = synced.get(&path);
+                if hash_file(&self.absolute_path(&path)).ok() != hash {
+                    eprintln!("{} was changed here, getting the server's copy back", path);
+                    fetch.push(path);
+                }
+            }
+        }
+
+        if fetch.is_empty() {
+            return None;
+        }
+        Some(Outgoing::Message(MessageType::RequestFiles { paths: fetch }))
+    }
+
+    // Deletes something the server doesn't have.
+    fn revert_local_file(&mut self, abs_path: &str) {
+        self.remove_local(abs_path, "the server");
+    }
+
+    // Deletes every file, directory and symlink that isn't in the other side's manifest.
+    // On the server, the deletes are passed on to the other clients too.
+    fn remove_unlisted(&mut self, entries: &[ManifestEntry], dirs: &[String], symlinks: &HashMap<String, String>) {
+        let listed: HashSet<&str> = entries
+            .iter()
+            .map(|entry| entry.path.as_str())
+            .chain(dirs.iter().map(String::as_str))
+            .chain(symlinks.keys().map(String::as_str))
+            .collect();
+
+        let mut unlisted: Vec<String> = self
+            .dirs
+            .iter()
+            .chain(&self.files)
+            .chain(self.symlinks.keys())
+            .filter(|abs_path| !listed.contains(self.relative_path(abs_path).as_str()))
+            .cloned()
+            .collect();
+
+        // Directories come before what's inside of them, which goes along with them, or is held with them
+        unlisted.sort();
+        let mut removed_dirs: Vec<String> = Vec::new();
+        for abs_path in unlisted {
+            if removed_dirs.iter().any(|dir| abs_path.starts_with(&format!("{}/", dir))) {
+                continue;
+            }
+
+            let was_dir = self.has_dir(Path::new(&abs_path));
+            if was_dir {
+                removed_dirs.push(abs_path.clone());
+            }
+            if !self.remove_local(&abs_path, "the other side") || !self.is_server {
+                continue;
+            }
+
+            let path = self.relative_path(&abs_path);
+            let delete = if was_dir { MessageType::DeleteDir { path, parent_hashes: None } } else { MessageType::DeleteEvent { path, parent_hash: None } };
+            self.forwards.push(Outgoing::Message(delete));
+        }
+    }
+
+    // Returns whether it was deleted, it may already be gone along with its directory. Like deletes
+    // from the other side, it's held instead once too many happen at once, see `brake.rs`.
+    fn remove_local(&mut self, abs_path: &str, missing_from: &str) -> bool {
+        let path = Path::new(abs_path);
+        let Ok(metadata) = path.symlink_metadata() else {
+            return false;
+        };
+
+        let relative_path = self.relative_path(abs_path);
+        if !self.brake.allow(self.files_under(&relative_path), self.files.len()) {
+            self.brake.hold(HeldDelete { path: relative_path, dir: metadata.is_dir(), incoming: true });
+            return false;
+        }
+
+        eprintln!("{} isn't on {}, deleting it", relative_path, missing_from);
+        self.keep_previous(&relative_path, None);
+        self.mark_as_modified(&relative_path);
+        let result = if metadata.is_dir() { std::fs::remove_dir_all(path) } else { std::fs::remove_file(path) };
+        if let Err(e) = result {
+            eprintln!("Failed to delete {}: {:?}", relative_path, e);
+            return false;
+        }
+        self.forget_path(path);
+        true
+    }
+
+    pub fn is_dir(&self, path: &str) -> bool {
+        self.has_dir(Path::new(&self.absolute_path(path)))
+    }
+
+    // Everything at `path` as if it had just been created, for a peer that couldn't see it until now.
+    pub fn make_tree_outgoing(&self, path: &str) -> Vec<Outgoing> {
+        let abs_path = self.absolute_path(path);
+        let prefix = format!("{}/", abs_path);
+        let inside = |p: &String| *p == abs_path || p.starts_with(&prefix);
+
+        let mut dirs: Vec<&String> = self.dirs.iter().filter(|d| inside(d)).collect();
+        dirs.sort();
+        let mut outgoing: Vec<Outgoing> = dirs
+            .into_iter()
+            .map(|dir| Outgoing::Message(MessageType::CreateDir { path: self.relative_path(dir) }))
+            .collect();
+        outgoing.extend(self.files.iter().filter(|f| inside(f)).map(|f| self.make_file_outgoing(f)));
+        outgoing.extend(self.symlinks.iter().filter(|(p, _)| inside(p)).map(|(p, target)| {
+            Outgoing::Message(MessageType::SymlinkEvent { path: self.relative_path(p), target: target.clone() })
+        }));
+        outgoing
+    }
+
+    fn make_file_outgoing(&self, abs_path: &str) -> Outgoing {
+        Outgoing::File {
+            path: self.relative_path(abs_path),
+            abs_path: abs_path.to_string(),
+            delta: FileWatcher::file_size(abs_path) > DELTA_THRESHOLD,
+            metadata: self.local_metadata(abs_path),
+        }
+    }
+
+    // Small files are bundled into syncs of up to `SYNC_BATCH_SIZE`, large ones are streamed separately.
+    // The writer reads them, so nothing is read while we hold on to the index. Only files we've
+    // indexed are sent, so a peer can't ask for anything outside of the tree.
+    fn get_requested_files(&self, paths: &[String]) -> Vec<Outgoing> {
+        let mut batches = Vec::new();
+        let mut files = Vec::new();
+        let mut metadata = HashMap::new();
+        let mut batch_size = 0;
+        let mut outgoing = Vec::new();
+        for path in paths {
+            let file = self.absolute_path(path);
+            if !self.files.contains(&file) {
+                eprintln!("Peer requested unknown file: {}", path);
+                continue;
+            }
+
+            // We were asked because the contents differ, so the peer may well have an older version
+            let size = FileWatcher::file_size(&file);
+            if size > DELTA_THRESHOLD {
+                let metadata = self.local_metadata(&file);
+                outgoing.push(Outgoing::File { path: path.clone(), abs_path: file, delta: true, metadata });
+                continue;
+            }
+
+            if batch_size + size > SYNC_BATCH_SIZE && !files.is_empty() {
+                batches.push(Outgoing::Files { files: std::mem::take(&mut files), metadata: std::mem::take(&mut metadata) });
+                batch_size = 0;
+            }
+            if let Some(file_metadata) = self.local_metadata(&file) {
+                metadata.insert(path.clone(), file_metadata);
+            }
+            files.push((path.clone(), file));
+            batch_size += size;
+        }
+
+        if !files.is_empty() {
+            batches.push(Outgoing::Files { files, metadata });
+        }
+        batches.extend(outgoing);
+        batches
+    }
+
+    fn file_size(path: &str) -> u64 {
+        std::fs::metadata(path).map(|m| m.len()).unwrap_or(0)
+    }
+
+    fn relative_path(&self, path: &str) -> String {
+        let path = Path::new(path);
+        let relative_path = path.strip_prefix(&self.root).unwrap_or(path);
+        let relative_path = relative_path.strip_prefix("/").unwrap_or(relative_path);
+        relative_path.to_string_lossy().to_string()
+    }
+
+    fn absolute_path(&self, path: &str) -> String {
+        format!("{}/{}", self.root, path)
+    }
+
+    // Moves a file or directory (including everything inside of it) to its new path in the index.
+    fn rename_in_index(&mut self, from: &Path, to: &Path) {
+        let from = from.to_string_lossy().to_string();
+        let to_path = to;
+        let to = to.to_string_lossy().to_string();
+        let from_prefix = format!("{}/", from);
+        let renamed = |path: &String| -> String {
+            if *path == from {
+                return to.clone();
+            }
+            match path.strip_prefix(&from_prefix) {
+                Some(rest) => format!("{}/{}", to, rest),
+                None => path.clone(),
+            }
+        };
+
+        // Whatever was at the destination got replaced
+        self.forget_path(to_path);
+        let known = self.files.contains(&from) || self.has_dir(Path::new(&from)) || self.symlinks.contains_key(&from);
+
+        self.files = self.files.iter().map(renamed).collect();
+        self.dirs = self.dirs.iter().map(renamed).collect();
+        self.file_hashes = self
+            .file_hashes
+            .iter()
+            .map(|(path, hash)| (renamed(path), *hash))
+            .collect();
+        self.file_metadata = self
+            .file_metadata
+            .iter()
+            .map(|(path, metadata)| (renamed(path), *metadata))
+            .collect();
+        self.symlinks = self
+            .symlinks
+            .iter()
+            .map(|(path, target)| (renamed(path), target.clone()))
+            .collect();
+
+        if !known {
+            if self.preserves_symlink(to_path) {
+                self.index_symlink(to_path);
+            } else if to_path.is_dir() {
+                self.remember_dir(&to);
+            } else if let Ok(hash) = hash_file(&to) {
+                self.remember_file(&to, hash);
+            }
+        }
+    }
+
+    // Removes a file, or a directory and everything inside of it, from the index.
+    fn forget_path(&mut self, path: &Path) {
+        let path = path.to_string_lossy().to_string();
+        let prefix = format!("{}/", path);
+        let forget = |p: &String| *p == path || p.starts_with(&prefix);
+        self.files.retain(|f| !forget(f));
+        self.dirs.retain(|d| !forget(d));
+        self.file_hashes.retain(|f, _| !forget(f));
+        self.file_metadata.retain(|f, _| !forget(f));
+        self.symlinks.retain(|f, _| !forget(f));
+    }
+
+    // Adds a directory and any of its parents to the index.
+    fn remember_dir(&mut self, path: &str) {
+        let mut path = Path::new(path);
+        while path.starts_with(&self.root) && path != Path::new(&self.root) {
+            let path_str = path.to_string_lossy().to_string();
+            self.dirs.insert(path_str);
+
+            match path.parent() {
+                Some(parent) => path = parent,
+                None => break,
+            }
+        }
+    }
+
+    // Keeps the index up to date with a file we've just written or sent.
+    fn remember_file(&mut self, path: &str, hash: u64) {
+        self.files.insert(path.to_string());
+        if let Some(parent) = Path::new(path).parent() {
+            self.remember_dir(&parent.to_string_lossy());
+        }
+        self.file_hashes.insert(path.to_string(), hash);
+        if let Some(metadata) = self.local_metadata(path) {
+            self.file_metadata.insert(path.to_string(), metadata);
+        }
+    }
+
+    // Called before we overwrite a file with a change from the other side that wasn't made to
+    // what we have. The server's copy always wins, so both sides end up with the same file:
+    // the server sends its copy back, the client keeps its own next to the server's.
+    // Returns what to reply with if the change shouldn't be applied.
+    fn resolve_conflict(&self, path: &str, parent_hash: Option<u64>, new_hash: u64, is_authorative: bool) -> Option<Vec<Outgoing>> {
+        if parent_hash.is_none() || !Path::new(path).is_file() {
+            return None;
+        }
+
+        let current_hash = hash_file(path);
+        if let Err(e) = current_hash {
+            eprintln!("Failed to hash {}, not overwriting it: {:?}", path, e);
+            return Some(vec![]);
+        }
+
+        let current_hash = current_hash.unwrap();
+        if Some(current_hash) == parent_hash || current_hash == new_hash {
+            return None;
+        }
+
+        if is_authorative {
+            eprintln!("Conflicting change to {}, sending our copy back", path);
+            return Some(vec![self.make_file_outgoing(path)]);
+        }
+
+        // Our copy is exactly what we don't want to keep
+        if self.local_changes == LocalChanges::Revert {
+            return None;
+        }
+
+        let copy = conflict_path(Path::new(path));
+        if let Err(e) = std::fs::copy(path, &copy) {
+            eprintln!("Failed to keep conflicting copy of {}, not overwriting it: {:?}", path, e);
+            return Some(vec![]);
+        }
+
+        eprintln!("Conflicting change to {}, keeping our copy as {}", path, copy.display());
+        None
+    }
+
+    // Like `resolve_conflict`, for a delete of a file or directory from the other side. `parents`
+    // has the hash the other side believes we have of every file it's deleting, anything else we
+    // have in there changed since. The server sends its copy back. The client keeps what changed
+    // where it is, as it's on its way to the server already, and only deletes the rest.
+    // Returns what to reply with if the delete shouldn't be applied as is.
+    fn resolve_delete_conflict(
+        &mut self,
+        path: &str,
+        parents: &HashMap<String, u64>,
+        is_authorative: bool,
+        peer: &mut PeerHashes,
+    ) -> Option<Vec<Outgoing>> {
+        let abs_path = self.absolute_path(path);
+        let prefix = format!("{}/", abs_path);
+        let inside = |p: &String| *p == abs_path || p.starts_with(&prefix);
+
+        let mut changed = Vec::new();
+        let mut unchanged = Vec::new();
+        for file in self.files.iter().filter(|file| inside(file)) {
+            let relative = self.relative_path(file);
+            match hash_file(file) {
+                Ok(hash) if parents.get(&relative) == Some(&hash) => unchanged.push(relative),
+                Ok(_) => changed.push(relative),
+                Err(e) => {
+                    eprintln!("Failed to hash {}, not deleting it: {:?}", file, e);
+                    return Some(vec![]);
+                }
+            }
+        }
+        if changed.is_empty() {
+            return None;
+        }
+
+        if is_authorative {
+            eprintln!("Conflicting delete of {}, sending our copy back", path);
+            return Some(self.make_tree_outgoing(path));
+        }
+
+        // Our changes are exactly what we don't want to keep
+        if self.local_changes == LocalChanges::Revert {
+            return None;
+        }
+
+        eprintln!("Conflicting delete of {}, keeping {} changed files", path, changed.len());
+        if !self.brake.allow(unchanged.len(), self.files.len()) {
+            for path in unchanged {
+                self.brake.hold(HeldDelete { path, dir: false, incoming: true });
+            }
+            return Some(vec![]);
+        }
+
+        for file in unchanged {
+            if self.apply_delete(&file, false) {
+                self.confirm_removed(peer, &file);
+            }
+        }
+
+        // Whatever directories are left empty go too, deepest first
+        let mut dirs: Vec<String> = self.dirs.iter().filter(|dir| inside(dir)).cloned().collect();
+        dirs.sort_by_key(|dir| std::cmp::Reverse(dir.len()));
+        for dir in dirs {
+            if std::fs::remove_dir(&dir).is_ok() {
+                self.forget_path(Path::new(&dir));
+                self.mark_as_modified(&self.relative_path(&dir));
+            }
+        }
+        Some(vec![])
+    }
+
+    // Our own metadata for a file, if we're meant to send it.
+    fn local_metadata(&self, path: &str) -> Option<FileMetadata> {
+        if !self.options.preserve_metadata {
+            return None;
+        }
+        FileMetadata::read(path)
+    }
+
+    // Applies metadata received from the other side to a file we've just written.
+    fn apply_metadata(&mut self, path: &str, metadata: Option<&FileMetadata>) {
+        if !self.options.preserve_metadata || metadata.is_none() {
+            return;
+        }
+
+        let metadata = metadata.unwrap();
+        if let Err(e) = metadata.apply(path) {
+            eprintln!("Failed to apply metadata to {}: {:?}", path, e);
+        }
+
+        // Remember what the file ended up with, so the resulting watcher event isn't sent back
+        if let Some(metadata) = FileMetadata::read(path) {
+            self.file_metadata.insert(path.to_string(), metadata);
+        }
+    }
+
+    // Adds a symlink to the index, if it points somewhere inside of the tree.
+    fn index_symlink(&mut self, path: &Path) {
+        let target = symlink::read_target(path);
+        if let Some(target) = target
+            && symlink::stays_inside(Path::new(&self.root), path, &target)
+        {
+            self.symlinks.insert(path.to_string_lossy().to_string(), target);
+        }
     }
 
     fn index_files(&mut self) {
-        let root = self.root.clone();
-        self.files = Walk::new(root)
+        let root = PathBuf::from(&self.root);
+        let entries = self
+            .walk(&root)
             .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.depth() > 0)
+            .collect::<Vec<_>>();
+        self.files = entries
+            .iter()
             .filter(|entry| entry.file_type().unwrap().is_file())
             .map(|entry| entry.path().to_string_lossy().to_string())
-            .collect::<Vec<String>>();
+            .collect::<HashSet<String>>();
+        self.dirs = entries
+            .iter()
+            .filter(|entry| entry.file_type().unwrap().is_dir())
+            .map(|entry| entry.path().to_string_lossy().to_string())
+            .collect::<HashSet<String>>();
+
+        self.symlinks.clear();
+        if self.options.symlinks == SymlinkMode::Preserve {
+            for entry in entries.iter().filter(|entry| entry.path_is_symlink()) {
+                self.index_symlink(entry.path());
+            }
+        }
 
-        self.file_hashes = HashMap::new();
+        // Hashes of files we already know about are kept, they describe what we last saw or sent.
+        // Rehashing them here would hide changes the watcher hasn't reported yet.
+        let mut file_hashes = std::mem::take(&mut self.file_hashes);
+        file_hashes.retain(|file, _| self.files.contains(file));
+        self.file_hashes = file_hashes;
+        let mut file_metadata = std::mem::take(&mut self.file_metadata);
+        file_metadata.retain(|file, _| self.files.contains(file));
+        self.file_metadata = file_metadata;
         for file in &self.files {
             let path = Path::new(file);
+            if self.file_hashes.contains_key(file) {
+                continue;
+            }
+
+            if let Some(metadata) = self.local_metadata(file) {
+                self.file_metadata.insert(file.to_string(), metadata);
+            }
+
             if path.exists() {
-                let contents = std::fs::read(file).unwrap();
-                self.file_hashes.insert(file.to_string(), fnv1a64(&contents));
+                match hash_file(file) {
+                    Ok(hash) => {
+                        self.file_hashes.insert(file.to_string(), hash);
+                    }
+                    Err(e) => eprintln!("Failed to hash {}: {:?}", file, e),
+                }
             } else {
                 eprintln!("File does not exist: {}", file);
             }
@@ -315,23 +1979,18 @@ impl FileWatcher {
     }
 
     fn mark_as_modified(&mut self, path: &str) {
+        // The watcher reports absolute paths, so that's what we check against
         self.ignore_files_until
-            .insert(path.to_string(), FileWatcher::get_now() + 500);
+            .insert(self.absolute_path(path), FileWatcher::get_now() + 500);
         eprintln!("Ignoring file for half a second: {}", path);
     }
 
-    fn has_changes(&self, path: &str, contents: &Vec<u8>) -> bool {
-        let new_hash = fnv1a64(&contents);
-        let old_hash = self.file_hashes.get(&path.to_string());
+    fn has_changes(&self, path: &str, new_hash: u64) -> bool {
+        let old_hash = self.file_hashes.get(path);
         let old_hash = *old_hash.unwrap_or(&0);
         old_hash != new_hash
     }
 
-    fn has_changes_read(&self, path: &str) -> bool {
-        let contents = std::fs::read(path).unwrap();
-        self.has_changes(path, &contents)
-    }
-
     fn get_now() -> u64 {
         std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
@@ -0,0 +1,61 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::conflict::PeerHashes;
+
+// Where the client keeps its own bookkeeping, inside of the synced folder.
+// Hidden files are never synced, so it stays on this machine.
+pub(crate) const STATE_DIR: &str = ".remote-fs";
+
+// What we know about the server's files, as of the last time we were connected. It outlives
+// the connection (and the process), so when we connect again we can tell the changes made
+// here while we were away apart from the ones made on the server. See `FileWatcher::reconcile`.
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq)]
+pub(crate) struct Journal {
+    // What the server had the last time it told us, through its manifest or a change of its own
+    pub synced: PeerHashes,
+
+    // What we had the last time we were connected. The server may or may not have received
+    // these, but if it has one of them, it hasn't changed the file since.
+    pub sent: HashMap<String, u64>,
+
+    // Files the server didn't have that we left alone, see `ExtraFiles::Report`. They're
+    // still extras the next time, unless they've changed since.
+    #[serde(default)]
+    pub extra: HashMap<String, u64>,
+}
+
+impl Journal {
+    // None if this folder was never in sync with the server.
+    pub fn load(root: &str) -> Option<Self> {
+        let file = std::fs::File::open(Journal::path(root)).ok()?;
+        match serde_json::from_reader(std::io::BufReader::new(file)) {
+            Ok(journal) => Some(journal),
+            Err(e) => {
+                eprintln!("Ignoring unreadable journal in '{}': {:?}", root, e);
+                None
+            }
+        }
+    }
+
+    pub fn save(&self, root: &str) -> std::io::Result<()> {
+        let path = Journal::path(root);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+
+        // Written next to it first, so we never leave half a journal behind
+        let temp = path.with_extension("json.tmp");
+        std::fs::write(&temp, serde_json::to_vec(self)?)?;
+        std::fs::rename(temp, path)
+    }
+
+    // Whether the server's copy of a file is one we already knew about, meaning nobody
+    // changed it on the server while we were away.
+    pub fn knows(&self, path: &str, hash: u64) -> bool {
+        self.synced.get(path) == Some(hash) || self.sent.get(path) == Some(&hash)
+    }
+
+    fn path(root: &str) -> PathBuf {
+        Path::new(root).join(STATE_DIR).join("journal.json")
+    }
+}
@@ -3,9 +3,24 @@ mod server;
 mod client;
 mod message_handler;
 mod config;
+mod protocol;
+mod transfer;
+mod delta;
+mod metadata;
+mod symlink;
+mod conflict;
+mod journal;
+mod tls;
+mod auth;
+mod paths;
+mod acl;
+mod brake;
+mod trash;
 
 use file_watcher::FileWatcher;
-use config::{Config, ServerConfig};
+use config::{Config, ExtraFiles, LocalChanges, ServerConfig, parse_address};
+use auth::Auth;
+use protocol::Authority;
 
 #[tokio::main]
 async fn main() {
@@ -25,27 +40,103 @@ async fn main() {
         },
 
         // client 127.0.0.1 /path/to/files/
+        // client 127.0.0.1:5344 /path/to/files/
         "client" => {
             if args.len() != 4 {
-                eprintln!("Usage: {} client <host> <path>", args[0]);
+                eprintln!("Usage: {} client <host[:port]> <path>", args[0]);
                 std::process::exit(1);
             }
 
-            let host = &args[2];
+            let (host, port) = match parse_address(&args[2]) {
+                Ok(address) => address,
+                Err(e) => {
+                    eprintln!("Invalid address '{}': {}", args[2], e);
+                    std::process::exit(1);
+                }
+            };
             let path = &args[3];
-            Config::new_client(host.to_string(), ServerConfig::DEFAULT_PORT, path.to_string())
+            Config::new_client(host, port.unwrap_or(ServerConfig::DEFAULT_PORT), path.to_string())
         },
 
-        // Create a new config file
-        "init" => {
+        // confirm-deletes /path/to/files/
+        "confirm-deletes" => {
             if args.len() != 3 {
+                eprintln!("Usage: {} confirm-deletes <path>", args[0]);
+                std::process::exit(1);
+            }
+
+            match brake::confirm(&args[2]) {
+                Ok(held) => {
+                    eprint!("{}", held);
+                    eprintln!("Confirmed, these go ahead within a second");
+                    std::process::exit(0);
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        },
+
+        // restore /path/to/files/file.txt
+        // restore /path/to/files/file.txt --version 2
+        "restore" => {
+            let version = match &args[2..] {
+                [_] => None,
+                [_, flag, version] if flag == "--version" => match version.parse::<usize>() {
+                    Ok(version) => Some(version),
+                    Err(_) => {
+                        eprintln!("Invalid version '{}', versions are numbered from 1, the newest", version);
+                        std::process::exit(1);
+                    }
+                },
+                _ => {
+                    eprintln!("Usage: {} restore <path> [--version <n>]", args[0]);
+                    std::process::exit(1);
+                }
+            };
+
+            match trash::restore(&args[2], version) {
+                Ok(()) => {
+                    eprintln!("Restored '{}'", args[2]);
+                    std::process::exit(0);
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        },
+
+        // Create a new config file, `init server --tls` also generates a certificate
+        "init" => {
+            let tls = args.len() == 4 && args[3] == "--tls";
+            if args.len() != 3 && !tls {
                 // next arg should've been server or client
                 eprintln!("Usage: {} init <server|client>", args[0]);
+                eprintln!("       {} init server --tls", args[0]);
                 std::process::exit(1);
             }
 
             let config_type = &args[2];
             match config_type.as_str() {
+                "server" if tls => {
+                    match Config::create_tls_server_config() {
+                        Ok(fingerprint) => {
+                            eprintln!("Server config created: config.json, with certificate server.crt and key server.key");
+                            eprintln!("Client config created: client.json, pinning certificate {} and with a token for the server", fingerprint);
+                            std::process::exit(0);
+                        }
+                        Err(e) => {
+                            eprintln!("Failed to create TLS server config: {}", e);
+                            std::process::exit(1);
+                        }
+                    }
+                },
+                _ if tls => {
+                    eprintln!("Only a server config can be created with --tls, it creates the client config that goes with it");
+                    std::process::exit(1);
+                },
                 "server" => {
                     Config::create_server_config();
                     eprintln!("Server config created: config.json");
@@ -67,7 +158,7 @@ async fn main() {
         // Otherwise, check if the first argument is a config file
         file => {
             let exists = std::fs::exists(file);
-            if let Ok(_) = exists {
+            if exists.is_ok() {
                 match Config::from_file(file) {
                     Ok(config) => config,
                     Err(e) => {
@@ -87,10 +178,16 @@ async fn main() {
         }
     };
 
-    start_from_config(&config).await;
+    // The server reads its tokens from the config file again when it changes
+    let config_path = match args[1].as_str() {
+        "server" | "client" | "init" => None,
+        file => Some(file),
+    };
+
+    start_from_config(&config, config_path).await;
 }
 
-async fn start_from_config(config: &Config) {
+async fn start_from_config(config: &Config, config_path: Option<&str>) {
     match config {
         Config::Server(server_config) => {
             let path = &server_config.location;
@@ -99,31 +196,73 @@ async fn start_from_config(config: &Config) {
                 std::process::exit(1);
             }
 
-            server::run(server_config.port, path).await;
+            let addresses = match server_config.bind_addresses() {
+                Ok(addresses) => addresses,
+                Err(e) => {
+                    eprintln!("Invalid bind address: {}", e);
+                    std::process::exit(1);
+                }
+            };
+
+            let acceptor = server_config.tls.as_ref().map(tls::acceptor).transpose();
+            if let Err(e) = &acceptor {
+                eprintln!("Failed to set up TLS: {}", e);
+                std::process::exit(1);
+            }
+
+            let auth = Auth::new(config_path, server_config.tokens.clone(), server_config.read_only);
+            if auth.is_open() {
+                eprintln!("No tokens configured, anyone who can connect has full access");
+            } else if server_config.tls.is_none() {
+                eprintln!("Tokens are sent in the clear, set up TLS to protect them");
+            }
+
+            server::run(&addresses, path, server_config.options.clone(), acceptor.unwrap(), auth).await;
         }
 
         Config::Client(client_config) => {
-            let ip = client_config.host.parse::<std::net::IpAddr>();
-            if ip.is_err() {
-                eprintln!("Invalid IP address: '{}'", client_config.host);
+            // Anything that isn't an IP address is taken to be a hostname
+            let host = client_config.host();
+            if host.is_empty() {
+                eprintln!("No host configured to connect to");
                 std::process::exit(1);
             }
 
-            let ip = ip.unwrap();
-            if ip.is_unspecified() {
+            if let Ok(ip) = host.parse::<std::net::IpAddr>()
+                && ip.is_unspecified()
+            {
                 eprintln!("Unspecified IP address {} is not allowed", ip);
                 std::process::exit(1);
             }
 
             let path = &client_config.location;
-            if !std::path::Path::new(path).exists() {
-                if let Err(e) = std::fs::create_dir_all(path) {
-                    eprintln!("Failed to create directory '{}': {}", path, e);
-                    std::process::exit(1);
-                }
+            if !std::path::Path::new(path).exists()
+                && let Err(e) = std::fs::create_dir_all(path)
+            {
+                eprintln!("Failed to create directory '{}': {}", path, e);
+                std::process::exit(1);
+            }
+
+            let connector = client_config.tls.as_ref().map(tls::connector).transpose();
+            if let Err(e) = &connector {
+                eprintln!("Failed to set up TLS: {}", e);
+                std::process::exit(1);
+            }
+
+            let options = &client_config.options;
+            if client_config.read_only && options.direction.source(options.authority) == Authority::Client {
+                eprintln!("A read-only client can't be the source, with {} as authority and {} as direction", options.authority, options.direction);
+                std::process::exit(1);
+            }
+
+            if client_config.revert_local_changes && client_config.local_changes() == LocalChanges::Send {
+                eprintln!("revert_local_changes only applies to a client that doesn't send its changes, ignoring it");
+            }
+            if client_config.extra_files == ExtraFiles::Upload && client_config.local_changes() != LocalChanges::Send {
+                eprintln!("This client doesn't send its changes, extra files will only be reported instead of uploaded");
             }
 
-            client::run(client_config.host.as_str(), path).await;
+            client::run(client_config, connector.unwrap()).await;
         }
     }
 }
@@ -1,34 +1,158 @@
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fmt};
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::tcp::OwnedReadHalf,
-};
+use std::{collections::HashMap, fmt, sync::Mutex};
+use crate::conflict::PeerHashes;
+use crate::delta::{BlockSignature, DELTA_MAX_SIZE, DeltaOp, compute_delta, literal_size};
+use crate::metadata::FileMetadata;
+use crate::protocol::{Authority, CAP_DELTA, CAP_METADATA, Direction, Session};
+use crate::transfer::{CHUNKED_THRESHOLD, send_file};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct ManifestEntry {
+    pub path: String,
+    pub size: u64,
+    pub mtime: u64,
+    pub hash: u64,
+    pub mode: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub(crate) enum MessageType {
-    Sync { files: HashMap<String, Vec<u8>> },
-    CreateEvent { path: String, contents: Vec<u8> },
-    ModifyEvent { path: String, contents: Vec<u8> },
-    DeleteEvent { path: String },
-    MoveEvent { old_path: String, new_path: String },
+    // Metadata is only sent when both sides have `preserve_metadata` enabled, see `CAP_METADATA`.
+    // `parent_hash` is the hash of the version the sender believes the receiver has, so the
+    // receiver can tell if its own copy changed in the meantime. See `conflict.rs`. For a move,
+    // that's the file it replaces at `new_path`.
+    Sync { files: HashMap<String, Vec<u8>>, metadata: HashMap<String, FileMetadata> },
+    CreateEvent { path: String, contents: Vec<u8>, metadata: Option<FileMetadata>, parent_hash: Option<u64> },
+    ModifyEvent { path: String, contents: Vec<u8>, metadata: Option<FileMetadata>, parent_hash: Option<u64> },
+    DeleteEvent { path: String, parent_hash: Option<u64> },
+    MoveEvent { old_path: String, new_path: String, parent_hash: Option<u64> },
+
+    // Handshake messages. Their position and fields must never change, as they are the
+    // only thing a peer running a different version is guaranteed to understand.
+    Hello { version: u32, min_version: u32, capabilities: Vec<String> },
+    Welcome { version: u32, capabilities: Vec<String> },
+    Error { message: String },
+
+    // Initial sync: the server lists what it has, the client asks for what it's missing.
+    // The requested files are answered with a `Sync`. Symlinks are listed with their targets.
+    Manifest { entries: Vec<ManifestEntry>, dirs: Vec<String>, symlinks: HashMap<String, String> },
+    RequestFiles { paths: Vec<String> },
+
+    // Large files are streamed in chunks instead of a single Create/ModifyEvent.
+    TransferBegin {
+        id: u64,
+        path: String,
+        size: u64,
+        metadata: Option<FileMetadata>,
+        parent_hash: Option<u64>,
+    },
+    TransferChunk { id: u64, offset: u64, data: Vec<u8> },
+    TransferEnd { id: u64, hash: u64 },
+
+    // Delta transfers: the sender asks for signatures of the receiver's copy, and answers
+    // with only the blocks that changed. An empty signature list means "send it whole".
+    // `size` is what the delta adds up to, the receiver stops at that.
+    SignatureRequest { path: String },
+    Signatures { path: String, base_hash: u64, block_size: u32, blocks: Vec<BlockSignature> },
+    DeltaEvent {
+        path: String,
+        base_hash: u64,
+        block_size: u32,
+        ops: Vec<DeltaOp>,
+        size: u64,
+        hash: u64,
+        metadata: Option<FileMetadata>,
+        parent_hash: Option<u64>,
+    },
+
+    // Directories are synced on their own, so empty ones make it across too.
+    // Directory renames are sent as a regular `MoveEvent`. `parent_hashes` has the parent of
+    // every file the sender believes is inside of a deleted directory.
+    CreateDir { path: String },
+    DeleteDir { path: String, parent_hashes: Option<HashMap<String, u64>> },
+
+    // Only the permissions or modification time of a file changed, e.g. after a `chmod`.
+    MetadataEvent { path: String, metadata: FileMetadata },
+
+    // A symlink was created or now points somewhere else. Targets are always relative.
+    SymlinkEvent { path: String, target: String },
+
+    // Sent by the client right after the handshake, before the server sends anything from its tree.
+    // The server answers with the name the token belongs to, or an `Error` if it's not accepted.
+    // Whoever is the source sends their manifest next, see `Direction`.
+    Authenticate { token: Option<String>, authority: Authority, direction: Direction },
+    Authenticated { name: Option<String> },
 }
 
-pub(crate) fn compose_data_message(event: &MessageType) -> Vec<u8> {
-    let event_data = serde_binary::to_vec(&event, serde_binary::binary_stream::Endian::Big);
-    if event_data.is_err() {
-        eprintln!("Failed to serialize event: {:?}", event);
-        return vec![];
+impl MessageType {
+    // Whether this changes something on the receiving side. Chunks don't count, they go nowhere
+    // without the `TransferBegin` before them.
+    pub(crate) fn is_change(&self) -> bool {
+        matches!(
+            self,
+            MessageType::Sync { .. }
+                | MessageType::CreateEvent { .. }
+                | MessageType::ModifyEvent { .. }
+                | MessageType::DeleteEvent { .. }
+                | MessageType::MoveEvent { .. }
+                | MessageType::TransferBegin { .. }
+                | MessageType::DeltaEvent { .. }
+                | MessageType::CreateDir { .. }
+                | MessageType::DeleteDir { .. }
+                | MessageType::MetadataEvent { .. }
+                | MessageType::SymlinkEvent { .. }
+        )
     }
+}
+
+// Something queued up to be sent to a peer.
+#[derive(Debug, Clone)]
+pub(crate) enum Outgoing {
+    Message(MessageType),
+
+    // A file that's too large to read into a single message. The writer streams it in chunks,
+    // or asks for signatures first when `delta` is set, as the other side likely has an older
+    // version we can diff against.
+    File { path: String, abs_path: String, delta: bool, metadata: Option<FileMetadata> },
 
-    let event_data = event_data.unwrap();
+    // Small files the other side asked for, which the writer reads and sends as a single `Sync`.
+    // Each batch is kept small, so neither side has all of them in memory at once.
+    Files { files: Vec<(String, String)>, metadata: HashMap<String, FileMetadata> },
+
+    // A file to send as a delta against the other side's copy, described by `blocks`. The writer
+    // reads the file and works out the delta, or sends it whole if too much of it changed.
+    Delta {
+        path: String,
+        abs_path: String,
+        base_hash: u64,
+        block_size: u32,
+        blocks: Vec<BlockSignature>,
+        metadata: Option<FileMetadata>,
+    },
+}
+
+// The largest message either side reads, anything larger is treated as a broken connection.
+// Files are only ever sent whole up to `CHUNKED_THRESHOLD`, and deltas up to `DELTA_MAX_SIZE`,
+// so this leaves plenty of room for large manifests.
+pub(crate) const MAX_MESSAGE_SIZE: usize = 256 * 1024 * 1024;
+
+pub(crate) fn compose_data_message(event: &MessageType) -> Result<Vec<u8>, String> {
+    let event_data = serde_binary::to_vec(&event, serde_binary::binary_stream::Endian::Big)
+        .map_err(|e| format!("Failed to serialize event: {:?}", e))?;
+
+    // The length has to fit in front of it, cutting it off would throw off everything after it.
+    // Neither would the other side read it.
     let msg_len = event_data.len();
-    let len = (msg_len as u32).to_be_bytes();
+    let len = u32::try_from(msg_len)
+        .ok()
+        .filter(|_| msg_len <= MAX_MESSAGE_SIZE)
+        .ok_or_else(|| format!("Message of {} bytes is too large to send", msg_len))?;
 
     let mut data = Vec::with_capacity(4 + msg_len);
-    data.extend_from_slice(&len);
+    data.extend_from_slice(&len.to_be_bytes());
     data.extend_from_slice(&event_data);
-    data
+    Ok(data)
 }
 
 pub(crate) fn parse_msg(data: &[u8]) -> Result<MessageType, serde_binary::Error> {
@@ -36,17 +160,34 @@ pub(crate) fn parse_msg(data: &[u8]) -> Result<MessageType, serde_binary::Error>
     Ok(event)
 }
 
-pub(crate) async fn read_msg(reader: &mut OwnedReadHalf) -> Result<MessageType, MessageError> {
+pub(crate) async fn read_msg<R>(reader: &mut R) -> Result<MessageType, MessageError>
+where
+    R: AsyncReadExt + Unpin,
+{
+    read_msg_up_to(reader, MAX_MESSAGE_SIZE).await
+}
+
+// Reads a message of at most `max_size` bytes. The length is checked before anything is
+// allocated for it, so a peer can't make us reserve memory by just claiming a large message.
+pub(crate) async fn read_msg_up_to<R>(reader: &mut R, max_size: usize) -> Result<MessageType, MessageError>
+where
+    R: AsyncReadExt + Unpin,
+{
+    // A single read isn't guaranteed to return the whole length once messages span multiple packets
     let mut len_buf = [0u8; 4];
-    let bytes_read = reader.read(&mut len_buf).await;
-    if let Err(_) = bytes_read {
+    let bytes_read = reader.read_exact(&mut len_buf).await;
+    if let Err(e) = bytes_read {
+        if e.kind() == std::io::ErrorKind::UnexpectedEof {
+            return Err(MessageError::disconnect_error("Disconnected"));
+        }
         return Err(MessageError::parse_error("Failed to read length"));
     }
-    if bytes_read.unwrap() == 0 {
-        return Err(MessageError::disconnect_error("Disconnected"));
-    }
 
     let len = u32::from_be_bytes(len_buf) as usize;
+    if len > max_size {
+        eprintln!("Refusing message of {} bytes, the limit is {}", len, max_size);
+        return Err(MessageError::disconnect_error("Message too large"));
+    }
     let mut msg_buf = vec![0u8; len];
     if reader.read_exact(&mut msg_buf).await.is_err() {
         return Err(MessageError::parse_error("Failed to read length"));
@@ -67,10 +208,13 @@ pub(crate) async fn write_msg<T>(writer: &mut T, msg: &MessageType) -> Result<()
 where
     T: AsyncWriteExt + Unpin,
 {
-    let msg_data = compose_data_message(msg);
-    if msg_data.is_empty() {
-        return Err(MessageError::parse_error("Failed to serialize message"));
-    }
+    let msg_data = match compose_data_message(msg) {
+        Ok(msg_data) => msg_data,
+        Err(e) => {
+            eprintln!("{}", e);
+            return Err(MessageError::parse_error("Failed to serialize message"));
+        }
+    };
 
     if writer.write_all(&msg_data).await.is_err() {
         return Err(MessageError::disconnect_error("Failed to write message"));
@@ -80,6 +224,111 @@ where
     Ok(())
 }
 
+pub(crate) async fn write_outgoing<W>(
+    writer: &mut W,
+    outgoing: &Outgoing,
+    session: &Session,
+    peer: &Mutex<PeerHashes>,
+) -> Result<(), MessageError>
+where
+    W: AsyncWriteExt + Unpin,
+{
+    match outgoing {
+        Outgoing::Message(msg) => {
+            if matches!(msg, MessageType::MetadataEvent { .. }) && !session.supports(CAP_METADATA) {
+                return Ok(());
+            }
+
+            let mut msg = msg.clone();
+            if !session.supports(CAP_METADATA) {
+                strip_metadata(&mut msg);
+            }
+            peer.lock().unwrap().stamp(&mut msg);
+            write_msg(writer, &msg).await
+        }
+        Outgoing::File { path, abs_path, delta, metadata } => {
+            // The other side won't read anything larger for signatures, it's sent whole. So is
+            // anything for a peer that doesn't take deltas.
+            let diffable = std::fs::metadata(abs_path).is_ok_and(|m| m.len() <= DELTA_MAX_SIZE);
+            if *delta && diffable && session.supports(CAP_DELTA) {
+                return write_msg(writer, &MessageType::SignatureRequest { path: path.clone() }).await;
+            }
+            let metadata = if session.supports(CAP_METADATA) { *metadata } else { None };
+            send_file(writer, path, abs_path, metadata, peer).await
+        }
+        Outgoing::Delta { path, abs_path, base_hash, block_size, blocks, metadata } => {
+            let metadata = if session.supports(CAP_METADATA) { *metadata } else { None };
+
+            // Reading and diffing a large file takes a while, so it's kept off the runtime
+            let (file, block_size, blocks) = (abs_path.clone(), *block_size, blocks.clone());
+            let delta = tokio::task::spawn_blocking(move || {
+                let mut reader = std::io::BufReader::new(std::fs::File::open(file)?);
+                compute_delta(&mut reader, block_size, &blocks, CHUNKED_THRESHOLD)
+            })
+            .await;
+
+            let delta = match delta {
+                Ok(Ok(Some(delta))) => delta,
+                // Too much of it changed for a delta to be worth it
+                Ok(Ok(None)) => return send_file(writer, path, abs_path, metadata, peer).await,
+                Ok(Err(e)) => {
+                    eprintln!("Failed to read file {}: {:?}", abs_path, e);
+                    return Err(MessageError::file_error("Failed to read file"));
+                }
+                Err(e) => {
+                    eprintln!("Failed to compute delta for {}: {:?}", abs_path, e);
+                    return Err(MessageError::file_error("Failed to compute delta"));
+                }
+            };
+
+            eprintln!("Sending delta for {}, {} of {} bytes changed", path, literal_size(&delta.ops), delta.size);
+            let mut msg = MessageType::DeltaEvent {
+                path: path.clone(),
+                base_hash: *base_hash,
+                block_size,
+                ops: delta.ops,
+                size: delta.size,
+                hash: delta.hash,
+                metadata,
+                parent_hash: None,
+            };
+            peer.lock().unwrap().stamp(&mut msg);
+            write_msg(writer, &msg).await
+        }
+        Outgoing::Files { files, metadata } => {
+            let mut contents = HashMap::new();
+            for (path, abs_path) in files {
+                match std::fs::read(abs_path) {
+                    Ok(data) => {
+                        contents.insert(path.clone(), data);
+                    }
+                    Err(e) => eprintln!("Could not read file {}: {:?}", abs_path, e),
+                }
+            }
+            if contents.is_empty() {
+                return Ok(());
+            }
+
+            let mut metadata = metadata.clone();
+            metadata.retain(|path, _| contents.contains_key(path) && session.supports(CAP_METADATA));
+            let mut msg = MessageType::Sync { files: contents, metadata };
+            peer.lock().unwrap().stamp(&mut msg);
+            write_msg(writer, &msg).await
+        }
+    }
+}
+
+// Leaves out the metadata of a change, for a peer that doesn't sync it.
+fn strip_metadata(msg: &mut MessageType) {
+    match msg {
+        MessageType::Sync { metadata, .. } => metadata.clear(),
+        MessageType::CreateEvent { metadata, .. }
+        | MessageType::ModifyEvent { metadata, .. }
+        | MessageType::DeltaEvent { metadata, .. } => *metadata = None,
+        _ => {}
+    }
+}
+
 pub(crate) struct MessageError {
     msg: String,
     is_disconnected: bool,
@@ -100,6 +349,13 @@ impl MessageError {
         }
     }
 
+    pub fn file_error(msg: &str) -> Self {
+        MessageError {
+            msg: msg.to_string(),
+            is_disconnected: false,
+        }
+    }
+
     #[allow(dead_code)]
     pub fn is_disconnected(&self) -> bool {
         self.is_disconnected
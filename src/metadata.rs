@@ -0,0 +1,61 @@
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, UNIX_EPOCH};
+
+// Read, write and execute for the owner, group and others, nothing else
+#[cfg(unix)]
+const MODE_MASK: u32 = 0o777;
+
+// The parts of a file's metadata we keep in sync. Modification times are in milliseconds
+// since the epoch, mode is the unix permission bits (always 0 on other platforms).
+// Setuid, setgid and sticky bits aren't synced, a peer could otherwise hand us a setuid binary.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FileMetadata {
+    pub mode: u32,
+    pub mtime: u64,
+}
+
+impl FileMetadata {
+    pub fn read(path: &str) -> Option<Self> {
+        let metadata = std::fs::metadata(path).ok()?;
+        Some(FileMetadata::from(&metadata))
+    }
+
+    pub fn from(metadata: &std::fs::Metadata) -> Self {
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+
+        FileMetadata { mode: mode_of(metadata), mtime }
+    }
+
+    pub fn apply(&self, path: &str) -> std::io::Result<()> {
+        // Unix doesn't need write access to set the time, which matters for read-only files
+        #[cfg(unix)]
+        let file = std::fs::File::open(path)?;
+        #[cfg(not(unix))]
+        let file = std::fs::File::options().write(true).open(path)?;
+        file.set_modified(UNIX_EPOCH + Duration::from_millis(self.mtime))?;
+
+        #[cfg(unix)]
+        if self.mode != 0 {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(self.mode & MODE_MASK))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn mode_of(metadata: &std::fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & MODE_MASK
+}
+
+#[cfg(not(unix))]
+fn mode_of(_metadata: &std::fs::Metadata) -> u32 {
+    0
+}
@@ -0,0 +1,369 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::{Component, Path};
+
+use crate::journal::STATE_DIR;
+use crate::message_handler::MessageType;
+use crate::symlink;
+
+// Turns a path the other side sent us into the form we use ourselves, relative to the root
+// and separated by `/`. Anything that could end up outside of the root is refused.
+pub(crate) fn normalize(path: &str) -> Result<String, String> {
+    if path.contains('\0') {
+        return Err(format!("{:?} contains a NUL byte", path));
+    }
+
+    let mut parts = Vec::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_string_lossy()),
+            Component::CurDir => {}
+            Component::ParentDir => return Err(format!("'{}' refers to a parent directory", path)),
+            Component::RootDir | Component::Prefix(_) => return Err(format!("'{}' is an absolute path", path)),
+        }
+    }
+
+    if parts.is_empty() {
+        return Err(format!("'{}' is not a path inside of the tree", path));
+    }
+    if parts[0] == STATE_DIR {
+        return Err(format!("'{}' is reserved for our own bookkeeping", path));
+    }
+    Ok(parts.join("/"))
+}
+
+// Whether getting to `path` means going through a symlink that leads out of `root`. The last
+// component only counts if it's about to be followed, a link itself can be removed or replaced.
+pub(crate) fn check_symlinks(root: &Path, path: &str, follow_last: bool) -> Result<(), String> {
+    let parts: Vec<&str> = path.split('/').collect();
+    let mut current = root.to_path_buf();
+    for (i, part) in parts.iter().enumerate() {
+        current.push(part);
+        if i + 1 == parts.len() && !follow_last {
+            break;
+        }
+
+        if symlink::is_symlink(&current) {
+            if !symlink::resolves_inside(root, &current) {
+                return Err(format!("'{}' goes through a symlink that leads outside of the tree", path));
+            }
+        } else if !current.exists() {
+            // Nothing below this exists yet, so there's nothing to follow either
+            break;
+        }
+    }
+    Ok(())
+}
+
+// Where `path` really is once every symlink on the way to it is followed, relative to `root`.
+// Like in `check_symlinks`, the last component is only followed if `follow_last` is set.
+// None if that's outside of the root, or goes through a link that doesn't lead anywhere.
+pub(crate) fn resolve(root: &Path, path: &str, follow_last: bool) -> Option<String> {
+    let root = root.canonicalize().ok()?;
+    let (dir, last) = match path.rsplit_once('/') {
+        _ if follow_last => (path, None),
+        Some((dir, last)) => (dir, Some(last)),
+        None => ("", Some(path)),
+    };
+
+    // Whatever doesn't exist yet has nothing to follow
+    let mut existing = root.join(dir);
+    let mut missing = Vec::new();
+    let mut resolved = loop {
+        match existing.canonicalize() {
+            Ok(resolved) => break resolved,
+            Err(_) if existing.symlink_metadata().is_ok() => return None,
+            Err(_) => {
+                missing.push(existing.file_name()?.to_os_string());
+                existing.pop();
+            }
+        }
+    };
+    resolved.extend(missing.iter().rev());
+    resolved.extend(last);
+
+    let relative = resolved.strip_prefix(&root).ok()?;
+    let parts: Vec<_> = relative.components().map(|c| c.as_os_str().to_string_lossy()).collect();
+    Some(parts.join("/"))
+}
+
+// Normalizes and checks every path in a message from the other side. The message is only
+// copied if a path had to be rewritten, which our own peers never need.
+pub(crate) fn validate<'a>(root: &Path, msg: &'a MessageType) -> Result<Cow<'a, MessageType>, String> {
+    let mut rewrite = false;
+    for (path, follow) in paths(msg) {
+        let normalized = normalize(path)?;
+        check_symlinks(root, &normalized, follow)?;
+        rewrite |= normalized != path;
+    }
+
+    if !rewrite {
+        return Ok(Cow::Borrowed(msg));
+    }
+
+    let mut msg = msg.clone();
+    rewrite_paths(&mut msg, |path| {
+        if let Ok(normalized) = normalize(path) {
+            *path = normalized;
+        }
+    });
+    Ok(Cow::Owned(msg))
+}
+
+// Every path in a message, and whether we'd follow it if it were a symlink.
+// Removing, moving or replacing something doesn't follow it, reading or writing it does.
+pub(crate) fn paths(msg: &MessageType) -> Vec<(&str, bool)> {
+    match msg {
+        MessageType::Sync { files, metadata } => files.keys().chain(metadata.keys()).map(|p| (p.as_str(), true)).collect(),
+        MessageType::CreateEvent { path, .. }
+        | MessageType::ModifyEvent { path, .. }
+        | MessageType::CreateDir { path }
+        | MessageType::TransferBegin { path, .. }
+        | MessageType::SignatureRequest { path }
+        | MessageType::Signatures { path, .. }
+        | MessageType::DeltaEvent { path, .. }
+        | MessageType::MetadataEvent { path, .. } => vec![(path, true)],
+        MessageType::DeleteEvent { path, .. } | MessageType::DeleteDir { path, .. } | MessageType::SymlinkEvent { path, .. } => {
+            vec![(path, false)]
+        }
+        MessageType::MoveEvent { old_path, new_path, .. } => vec![(old_path, false), (new_path, false)],
+        MessageType::Manifest { entries, dirs, symlinks } => entries
+            .iter()
+            .map(|e| (e.path.as_str(), true))
+            .chain(dirs.iter().map(|d| (d.as_str(), true)))
+            .chain(symlinks.keys().map(|p| (p.as_str(), false)))
+            .collect(),
+        MessageType::RequestFiles { paths } => paths.iter().map(|p| (p.as_str(), true)).collect(),
+        MessageType::Hello { .. }
+        | MessageType::Welcome { .. }
+        | MessageType::Error { .. }
+        | MessageType::Authenticate { .. }
+        | MessageType::Authenticated { .. }
+        | MessageType::TransferChunk { .. }
+        | MessageType::TransferEnd { .. } => vec![],
+    }
+}
+
+fn rewrite_paths(msg: &mut MessageType, mut rewrite: impl FnMut(&mut String)) {
+    match msg {
+        MessageType::Sync { files, metadata } => {
+            rewrite_keys(files, &mut rewrite);
+            rewrite_keys(metadata, &mut rewrite);
+        }
+        MessageType::CreateEvent { path, .. }
+        | MessageType::ModifyEvent { path, .. }
+        | MessageType::CreateDir { path }
+        | MessageType::TransferBegin { path, .. }
+        | MessageType::SignatureRequest { path }
+        | MessageType::Signatures { path, .. }
+        | MessageType::DeltaEvent { path, .. }
+        | MessageType::MetadataEvent { path, .. }
+        | MessageType::DeleteEvent { path, .. }
+        | MessageType::DeleteDir { path, .. }
+        | MessageType::SymlinkEvent { path, .. } => rewrite(path),
+        MessageType::MoveEvent { old_path, new_path, .. } => {
+            rewrite(old_path);
+            rewrite(new_path);
+        }
+        MessageType::Manifest { entries, dirs, symlinks } => {
+            entries.iter_mut().for_each(|e| rewrite(&mut e.path));
+            dirs.iter_mut().for_each(&mut rewrite);
+            rewrite_keys(symlinks, &mut rewrite);
+        }
+        MessageType::RequestFiles { paths } => paths.iter_mut().for_each(&mut rewrite),
+        _ => {}
+    }
+}
+
+fn rewrite_keys<V>(map: &mut HashMap<String, V>, rewrite: &mut impl FnMut(&mut String)) {
+    *map = map
+        .drain()
+        .map(|(mut path, value)| {
+            rewrite(&mut path);
+            (path, value)
+        })
+        .collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // A `root` to sync and an `outside` next to it, removed again when the test is done.
+    struct Tree(PathBuf);
+
+    impl Tree {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("remote-fs-paths-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(dir.join("root/inside")).unwrap();
+            std::fs::create_dir_all(dir.join("outside")).unwrap();
+            std::fs::write(dir.join("root/inside/file"), "inside").unwrap();
+            std::fs::write(dir.join("outside/secret"), "outside").unwrap();
+            Tree(dir)
+        }
+
+        fn root(&self) -> PathBuf {
+            self.0.join("root")
+        }
+
+        #[cfg(unix)]
+        fn link(&self, path: &str, target: &str) {
+            std::os::unix::fs::symlink(target, self.root().join(path)).unwrap();
+        }
+    }
+
+    impl Drop for Tree {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn normalizes_to_slash_separated_relative_paths() {
+        assert_eq!(normalize("a/b").unwrap(), "a/b");
+        assert_eq!(normalize("./a//b/./c").unwrap(), "a/b/c");
+        assert_eq!(normalize("a/b/").unwrap(), "a/b");
+    }
+
+    #[test]
+    fn refuses_parent_dirs() {
+        assert!(normalize("..").is_err());
+        assert!(normalize("../secret").is_err());
+        assert!(normalize("a/../../secret").is_err());
+        // Even when it would stay inside, nobody of ours sends these
+        assert!(normalize("a/../b").is_err());
+    }
+
+    #[test]
+    fn refuses_absolute_paths() {
+        assert!(normalize("/etc/passwd").is_err());
+        assert!(normalize("//etc/passwd").is_err());
+    }
+
+    #[test]
+    fn refuses_nul_bytes() {
+        assert!(normalize("a\0b").is_err());
+        assert!(normalize("a/b\0").is_err());
+    }
+
+    #[test]
+    fn refuses_empty_paths() {
+        assert!(normalize("").is_err());
+        assert!(normalize(".").is_err());
+        assert!(normalize("./").is_err());
+    }
+
+    #[test]
+    fn refuses_the_state_dir() {
+        assert!(normalize(STATE_DIR).is_err());
+        assert!(normalize(&format!("{}/journal.json", STATE_DIR)).is_err());
+        assert!(normalize(&format!("./{}/journal.json", STATE_DIR)).is_err());
+        // Only ours at the root is reserved
+        assert!(normalize(&format!("a/{}/journal.json", STATE_DIR)).is_ok());
+    }
+
+    #[test]
+    fn allows_paths_that_dont_exist_yet() {
+        let tree = Tree::new("missing");
+        assert!(check_symlinks(&tree.root(), "new/dir/file", true).is_ok());
+        assert_eq!(resolve(&tree.root(), "new/dir/file", true).as_deref(), Some("new/dir/file"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_an_escaping_symlink_in_the_middle() {
+        let tree = Tree::new("escaping");
+        tree.link("out", "../outside");
+
+        assert!(check_symlinks(&tree.root(), "out/secret", true).is_err());
+        assert!(check_symlinks(&tree.root(), "out/secret", false).is_err());
+        assert!(check_symlinks(&tree.root(), "out/new/file", false).is_err());
+        assert_eq!(resolve(&tree.root(), "out/secret", false), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_follows_the_last_component_when_asked() {
+        let tree = Tree::new("last");
+        tree.link("out", "../outside");
+
+        // The link itself can be deleted or replaced, just not written through
+        assert!(check_symlinks(&tree.root(), "out", false).is_ok());
+        assert!(check_symlinks(&tree.root(), "out", true).is_err());
+        assert_eq!(resolve(&tree.root(), "out", false).as_deref(), Some("out"));
+        assert_eq!(resolve(&tree.root(), "out", true), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn follows_symlinks_that_stay_inside() {
+        let tree = Tree::new("inside");
+        tree.link("alias", "inside");
+
+        assert!(check_symlinks(&tree.root(), "alias/file", true).is_ok());
+        assert_eq!(resolve(&tree.root(), "alias/file", true).as_deref(), Some("inside/file"));
+        assert_eq!(resolve(&tree.root(), "alias/new", false).as_deref(), Some("inside/new"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolves_dangling_symlinks_to_nothing() {
+        let tree = Tree::new("dangling");
+        tree.link("dangling", "nowhere");
+
+        assert_eq!(resolve(&tree.root(), "dangling/file", false), None);
+    }
+
+    #[test]
+    fn validate_only_copies_messages_it_rewrites() {
+        let tree = Tree::new("validate");
+        let msg = MessageType::CreateDir { path: "inside/new".to_string() };
+        assert!(matches!(validate(&tree.root(), &msg), Ok(Cow::Borrowed(_))));
+
+        let msg = MessageType::MoveEvent {
+            old_path: "./inside/file".to_string(),
+            new_path: "inside//moved".to_string(),
+            parent_hash: None,
+        };
+        match validate(&tree.root(), &msg).unwrap() {
+            Cow::Owned(MessageType::MoveEvent { old_path, new_path, .. }) => {
+                assert_eq!(old_path, "inside/file");
+                assert_eq!(new_path, "inside/moved");
+            }
+            other => panic!("Expected a rewritten move, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn validate_refuses_any_bad_path_in_a_message() {
+        let tree = Tree::new("refuse");
+        let msg = MessageType::MoveEvent {
+            old_path: "inside/file".to_string(),
+            new_path: "../outside/file".to_string(),
+            parent_hash: None,
+        };
+        assert!(validate(&tree.root(), &msg).is_err());
+
+        let msg = MessageType::RequestFiles { paths: vec!["inside/file".to_string(), format!("{}/journal.json", STATE_DIR)] };
+        assert!(validate(&tree.root(), &msg).is_err());
+
+        let msg = MessageType::DeleteEvent { path: "/etc/passwd".to_string(), parent_hash: None };
+        assert!(validate(&tree.root(), &msg).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn validate_refuses_writes_through_an_escaping_symlink() {
+        let tree = Tree::new("through");
+        tree.link("out", "../outside");
+
+        let msg = MessageType::CreateDir { path: "out/dir".to_string() };
+        assert!(validate(&tree.root(), &msg).is_err());
+
+        // Deleting the link itself is fine, it doesn't touch what it points to
+        let msg = MessageType::DeleteEvent { path: "out".to_string(), parent_hash: None };
+        assert!(validate(&tree.root(), &msg).is_ok());
+    }
+}
@@ -0,0 +1,418 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use std::sync::Mutex;
+
+use crate::auth::{Auth, Identity};
+use crate::message_handler::{MessageType, read_msg_up_to, write_msg};
+
+// The version of the wire protocol this binary speaks. Bump this whenever `MessageType`
+// changes in a way older peers can't parse.
+pub(crate) const PROTOCOL_VERSION: u32 = 13;
+
+// The oldest protocol version this binary can still talk to. serde_binary can't skip fields or
+// variants it doesn't know, so this is only raised for changes to the layout of `MessageType`.
+// Anything a peer can do without goes behind a capability instead, so binaries of different
+// ages keep talking to each other.
+pub(crate) const MIN_PROTOCOL_VERSION: u32 = 13;
+
+// Optional features this binary supports. Capabilities are exchanged as strings so an
+// older peer can ignore the ones it doesn't know about instead of failing to parse them.
+pub(crate) const CAPABILITIES: &[&str] = &[CAP_DELTA, CAP_METADATA];
+
+// Modified files can be sent as a delta against the other side's copy, see `delta.rs`.
+pub(crate) const CAP_DELTA: &str = "delta";
+
+// Permissions and modification times are synced, see `metadata.rs`. Only offered with
+// `preserve_metadata` enabled, a peer without it is sent contents alone.
+pub(crate) const CAP_METADATA: &str = "metadata";
+
+pub(crate) const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The largest message read before the client is authenticated. None of the handshake messages
+// come close, and nobody gets to make us allocate more before we know who they are.
+const HANDSHAKE_MAX_SIZE: usize = 64 * 1024;
+
+// Which side's tree is the source of truth. Whatever the other side is missing or has a
+// different version of is replaced with its copy, and conflicts are resolved its way.
+// Normally that's the server, but a client can push its tree instead, e.g. to build what's
+// on a laptop on a remote machine. Both sides have to agree on it.
+// Sent as a string, serde_binary leaves a byte behind after a unit variant that throws off
+// whatever comes next in the message.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) enum Authority {
+    #[default]
+    Server,
+    Client,
+}
+
+impl std::fmt::Display for Authority {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Authority::Server => write!(f, "server"),
+            Authority::Client => write!(f, "client"),
+        }
+    }
+}
+
+impl TryFrom<String> for Authority {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "server" => Ok(Authority::Server),
+            "client" => Ok(Authority::Client),
+            _ => Err(format!("'{}' is not an authority, expected server or client", value)),
+        }
+    }
+}
+
+impl From<Authority> for String {
+    fn from(authority: Authority) -> Self {
+        authority.to_string()
+    }
+}
+
+// Which way changes go, as seen from the client. The side they come from is the source,
+// it goes first with its manifest and its copy wins. The other side never sends changes,
+// and if it tries anyway, they're refused. Sent as a string, like `Authority`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) enum Direction {
+    // From the client to the server
+    Push,
+    // From the server to the client
+    Pull,
+    // Both ways, the authoritative side is the source
+    #[default]
+    TwoWay,
+    // From the authoritative side, which the other side is made identical to. Anything it
+    // doesn't have is deleted.
+    Mirror,
+}
+
+impl Direction {
+    pub(crate) fn source(self, authority: Authority) -> Authority {
+        match self {
+            Direction::Push => Authority::Client,
+            Direction::Pull => Authority::Server,
+            Direction::TwoWay | Direction::Mirror => authority,
+        }
+    }
+
+    // Whether changes made on `side` go to the other side
+    pub(crate) fn sends(self, authority: Authority, side: Authority) -> bool {
+        self == Direction::TwoWay || self.source(authority) == side
+    }
+
+    // Whether a server set to this direction lets a client sync in `requested`. A two-way server
+    // lets clients pick, except for mirroring, which has to be set up on both sides.
+    fn permits(self, requested: Direction) -> bool {
+        self == requested || (self == Direction::TwoWay && requested != Direction::Mirror)
+    }
+}
+
+impl std::fmt::Display for Direction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Direction::Push => write!(f, "push"),
+            Direction::Pull => write!(f, "pull"),
+            Direction::TwoWay => write!(f, "two-way"),
+            Direction::Mirror => write!(f, "mirror"),
+        }
+    }
+}
+
+impl TryFrom<String> for Direction {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "push" => Ok(Direction::Push),
+            "pull" => Ok(Direction::Pull),
+            "two-way" => Ok(Direction::TwoWay),
+            "mirror" => Ok(Direction::Mirror),
+            _ => Err(format!("'{}' is not a direction, expected push, pull, two-way or mirror", value)),
+        }
+    }
+}
+
+impl From<Direction> for String {
+    fn from(direction: Direction) -> Self {
+        direction.to_string()
+    }
+}
+
+// The result of a successful handshake: what both sides agreed to speak.
+#[derive(Debug, Clone)]
+pub(crate) struct Session {
+    pub version: u32,
+    pub capabilities: Vec<String>,
+}
+
+impl Session {
+    fn negotiate(version: u32, min_version: u32, capabilities: &[String], local: &[String]) -> Result<Self, String> {
+        let negotiated = version.min(PROTOCOL_VERSION);
+        let required = min_version.max(MIN_PROTOCOL_VERSION);
+        if negotiated < required {
+            return Err(format!(
+                "Incompatible protocol version: peer speaks {} (min {}), we speak {} (min {})",
+                version, min_version, PROTOCOL_VERSION, MIN_PROTOCOL_VERSION
+            ));
+        }
+
+        let capabilities = capabilities
+            .iter()
+            .filter(|c| local.contains(c))
+            .cloned()
+            .collect();
+
+        Ok(Session { version: negotiated, capabilities })
+    }
+
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+}
+
+// The capabilities we offer, given whether we're syncing metadata at all.
+pub(crate) fn local_capabilities(preserve_metadata: bool) -> Vec<String> {
+    CAPABILITIES
+        .iter()
+        .filter(|c| preserve_metadata || **c != CAP_METADATA)
+        .map(|c| c.to_string())
+        .collect()
+}
+
+// Sends our Hello and waits for the server to either welcome or reject us.
+pub(crate) async fn client_handshake<R, W>(reader: &mut R, writer: &mut W, capabilities: &[String]) -> Result<Session, String>
+where
+    R: AsyncReadExt + Unpin,
+    W: AsyncWriteExt + Unpin,
+{
+    let hello = MessageType::Hello {
+        version: PROTOCOL_VERSION,
+        min_version: MIN_PROTOCOL_VERSION,
+        capabilities: capabilities.to_vec(),
+    };
+    write_msg(writer, &hello).await.map_err(|e| format!("Failed to send hello: {:?}", e))?;
+
+    let reply = tokio::time::timeout(HANDSHAKE_TIMEOUT, read_msg_up_to(reader, HANDSHAKE_MAX_SIZE)).await;
+    let reply = match reply {
+        Ok(Ok(reply)) => reply,
+        Ok(Err(e)) => return Err(format!("Failed to read handshake reply: {:?}", e)),
+        Err(_) => return Err("Timed out waiting for handshake reply".to_string()),
+    };
+
+    match reply {
+        // The server already picked a version, so it's the only one we can accept
+        MessageType::Welcome { version, capabilities: accepted } => {
+            Session::negotiate(version, version, &accepted, capabilities)
+        }
+        MessageType::Error { message } => Err(format!("Server rejected connection: {}", message)),
+        _ => Err("Server did not answer our hello, it probably predates the handshake".to_string()),
+    }
+}
+
+// Waits for the client's Hello and answers it. On failure the client is told why before
+// we hang up, so it can report something more useful than a dropped connection.
+pub(crate) async fn server_handshake<R, W>(reader: &mut R, writer: &mut W, capabilities: &[String]) -> Result<Session, String>
+where
+    R: AsyncReadExt + Unpin,
+    W: AsyncWriteExt + Unpin,
+{
+    let hello = tokio::time::timeout(HANDSHAKE_TIMEOUT, read_msg_up_to(reader, HANDSHAKE_MAX_SIZE)).await;
+    let result = match hello {
+        Ok(Ok(MessageType::Hello { version, min_version, capabilities: offered })) => {
+            Session::negotiate(version, min_version, &offered, capabilities)
+        }
+        Ok(Ok(_)) => Err("Client did not start with a hello, it probably predates the handshake".to_string()),
+        Ok(Err(e)) => Err(format!("Failed to read hello: {:?}", e)),
+        Err(_) => Err("Timed out waiting for hello".to_string()),
+    };
+
+    let reply = match &result {
+        Ok(session) => MessageType::Welcome {
+            version: session.version,
+            capabilities: session.capabilities.clone(),
+        },
+        Err(e) => MessageType::Error { message: e.clone() },
+    };
+
+    // A failure to reply is only worth reporting if the handshake itself went well.
+    if let Err(e) = write_msg(writer, &reply).await
+        && result.is_ok()
+    {
+        return Err(format!("Failed to send welcome: {:?}", e));
+    }
+
+    result
+}
+
+// Sends our token, if we have one, and how we want to sync, and waits for the server to
+// accept both. Returns the name the server knows us by.
+pub(crate) async fn client_authenticate<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    token: Option<&str>,
+    authority: Authority,
+    direction: Direction,
+) -> Result<Option<String>, String>
+where
+    R: AsyncReadExt + Unpin,
+    W: AsyncWriteExt + Unpin,
+{
+    let authenticate = MessageType::Authenticate { token: token.map(str::to_string), authority, direction };
+    write_msg(writer, &authenticate).await.map_err(|e| format!("Failed to send token: {:?}", e))?;
+
+    let reply = tokio::time::timeout(HANDSHAKE_TIMEOUT, read_msg_up_to(reader, HANDSHAKE_MAX_SIZE)).await;
+    match reply {
+        Ok(Ok(MessageType::Authenticated { name })) => Ok(name),
+        Ok(Ok(MessageType::Error { message })) => Err(format!("Server rejected us: {}", message)),
+        Ok(Ok(_)) => Err("Server did not answer our token".to_string()),
+        Ok(Err(e)) => Err(format!("Failed to read authentication reply: {:?}", e)),
+        Err(_) => Err("Timed out waiting for authentication reply".to_string()),
+    }
+}
+
+// Waits for the client's token and checks it against the ones we accept, and that the client
+// agrees with us on who's authoritative and which way to sync. Returns who the client is and
+// the direction it picked. Like the handshake, the client is told why it's rejected before
+// we hang up.
+pub(crate) async fn server_authenticate<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    auth: &Mutex<Auth>,
+    authority: Authority,
+    direction: Direction,
+) -> Result<(Identity, Direction), String>
+where
+    R: AsyncReadExt + Unpin,
+    W: AsyncWriteExt + Unpin,
+{
+    let authenticate = tokio::time::timeout(HANDSHAKE_TIMEOUT, read_msg_up_to(reader, HANDSHAKE_MAX_SIZE)).await;
+    let result = match authenticate {
+        Ok(Ok(MessageType::Authenticate { token, authority: client_authority, direction: client_direction })) => {
+            let mut auth = auth.lock().unwrap();
+            auth.reload();
+            auth.authenticate(token.as_deref()).and_then(|identity| {
+                if client_authority != authority {
+                    return Err(format!("This server expects the {} to be authoritative", authority));
+                }
+                if !direction.permits(client_direction) {
+                    return Err(format!("This server only syncs {}, not {}", direction, client_direction));
+                }
+                if client_direction.source(authority) == Authority::Client && identity.access.read_only {
+                    return Err("A read-only client can't be the source".to_string());
+                }
+                // Mirroring would delete everything the client can't see
+                if client_direction == Direction::Mirror
+                    && authority == Authority::Client
+                    && identity.access.paths.is_some()
+                {
+                    return Err("A client that can't see everything can't mirror onto the server".to_string());
+                }
+                Ok((identity, client_direction))
+            })
+        }
+        Ok(Ok(_)) => Err("Client did not authenticate".to_string()),
+        Ok(Err(e)) => Err(format!("Failed to read token: {:?}", e)),
+        Err(_) => Err("Timed out waiting for token".to_string()),
+    };
+
+    let reply = match &result {
+        Ok((identity, _)) => MessageType::Authenticated { name: identity.name.clone() },
+        Err(e) => MessageType::Error { message: e.clone() },
+    };
+
+    if let Err(e) = write_msg(writer, &reply).await
+        && result.is_ok()
+    {
+        return Err(format!("Failed to confirm authentication: {:?}", e));
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn agrees_on_the_older_version() {
+        let local = local_capabilities(true);
+        let session = Session::negotiate(PROTOCOL_VERSION + 1, MIN_PROTOCOL_VERSION, &local, &local).unwrap();
+        assert_eq!(session.version, PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn refuses_versions_outside_of_the_range() {
+        let local = local_capabilities(true);
+        assert!(Session::negotiate(MIN_PROTOCOL_VERSION - 1, 1, &local, &local).is_err());
+        assert!(Session::negotiate(PROTOCOL_VERSION + 2, PROTOCOL_VERSION + 1, &local, &local).is_err());
+    }
+
+    #[test]
+    fn only_keeps_capabilities_both_sides_have() {
+        let offered = strings(&[CAP_DELTA, CAP_METADATA, "compression"]);
+        let session = Session::negotiate(PROTOCOL_VERSION, MIN_PROTOCOL_VERSION, &offered, &local_capabilities(false)).unwrap();
+        assert!(session.supports(CAP_DELTA));
+        assert!(!session.supports(CAP_METADATA));
+        assert!(!session.supports("compression"));
+    }
+
+    #[test]
+    fn only_offers_metadata_when_preserving_it() {
+        assert!(local_capabilities(true).contains(&CAP_METADATA.to_string()));
+        assert!(!local_capabilities(false).contains(&CAP_METADATA.to_string()));
+        assert!(local_capabilities(false).contains(&CAP_DELTA.to_string()));
+    }
+
+    #[test]
+    fn decides_which_way_changes_go() {
+        use Authority::{Client, Server};
+        use Direction::{Mirror, Pull, Push, TwoWay};
+
+        // Direction, authority, the source, and whether the server's and the client's changes are sent
+        let table = [
+            (Push, Server, Client, false, true),
+            (Push, Client, Client, false, true),
+            (Pull, Server, Server, true, false),
+            (Pull, Client, Server, true, false),
+            (TwoWay, Server, Server, true, true),
+            (TwoWay, Client, Client, true, true),
+            (Mirror, Server, Server, true, false),
+            (Mirror, Client, Client, false, true),
+        ];
+        for (direction, authority, source, server_sends, client_sends) in table {
+            let case = format!("{} with the {} authoritative", direction, authority);
+            assert_eq!(direction.source(authority), source, "{}", case);
+            assert_eq!(direction.sends(authority, Server), server_sends, "{}", case);
+            assert_eq!(direction.sends(authority, Client), client_sends, "{}", case);
+        }
+    }
+
+    #[test]
+    fn decides_which_directions_a_server_permits() {
+        use Direction::{Mirror, Pull, Push, TwoWay};
+
+        // The server's direction, then whether it permits push, pull, two-way and mirror
+        let table = [
+            (Push, [true, false, false, false]),
+            (Pull, [false, true, false, false]),
+            (TwoWay, [true, true, true, false]),
+            (Mirror, [false, false, false, true]),
+        ];
+        for (server, permitted) in table {
+            for (requested, permitted) in [Push, Pull, TwoWay, Mirror].into_iter().zip(permitted) {
+                assert_eq!(server.permits(requested), permitted, "{} server, {} client", server, requested);
+            }
+        }
+    }
+}
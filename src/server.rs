@@ -1,21 +1,82 @@
 use std::sync::{Arc, Mutex};
 use std::collections::HashMap;
 use tokio::sync::mpsc;
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpListener;
-use crate::message_handler::{ read_msg, write_msg, MessageType };
-
-pub(crate) async fn run(port: u16, root: &str) {
-    let listener = TcpListener::bind(("0.0.0.0", port)).await.unwrap();
-    let file_watcher = Arc::new(Mutex::new(crate::FileWatcher::new(root).unwrap()));
-    eprintln!("Server listening on port {}", port);
-    let clients: Arc<Mutex<HashMap<String, mpsc::UnboundedSender<Vec<u8>>>>> = Arc::new(Mutex::new(HashMap::new()));
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::AbortHandle;
+use crate::message_handler::{ read_msg, write_outgoing, MessageType, Outgoing };
+use crate::conflict::PeerHashes;
+use crate::config::SyncOptions;
+use crate::file_watcher::FileWatcher;
+use crate::auth::{Auth, Identity};
+use crate::protocol::{server_authenticate, server_handshake, Authority};
+use crate::tls;
+use crate::transfer::PeerTransfers;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio_rustls::TlsAcceptor;
+
+// How often to check the config file for tokens that were added or revoked
+const AUTH_RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+
+// How long to wait after a failed accept, errors like running out of file descriptors don't go
+// away immediately
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+type Clients = Arc<Mutex<HashMap<String, Client>>>;
+
+struct Client {
+    tx: mpsc::UnboundedSender<Outgoing>,
+    identity: Identity,
+
+    // Stops reading from the client, so it can be disconnected when its token is revoked
+    reader: AbortHandle,
+}
+
+pub(crate) async fn run(
+    addresses: &[SocketAddr],
+    root: &str,
+    options: SyncOptions,
+    acceptor: Option<TlsAcceptor>,
+    auth: Auth,
+) {
+    let mut listeners = Vec::new();
+    for address in addresses {
+        match listen(*address) {
+            Ok(listener) => listeners.push(listener),
+            Err(e) => {
+                eprintln!("Failed to listen on {}: {}", address, e);
+                std::process::exit(1);
+            }
+        }
+        eprintln!("Server listening on {}", address);
+    }
+
+    let file_watcher = crate::FileWatcher::new(root, options).unwrap_or_else(|e| {
+        eprintln!("Failed to watch '{}': {}", root, e);
+        std::process::exit(1);
+    });
+    let file_watcher = Arc::new(Mutex::new(file_watcher));
+    file_watcher.lock().unwrap().set_is_server();
+    let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
     let writer_clients = clients.clone();
     let writer_file_watcher = file_watcher.clone();
 
     tokio::spawn(async move {
         eprintln!("File watcher started watching {}", writer_file_watcher.lock().unwrap().root);
         loop {
+            // Deletes the brake was holding on to, once they're confirmed
+            let released = writer_file_watcher.lock().unwrap().take_released();
+            if !released.is_empty() {
+                for (addr, client) in writer_clients.lock().unwrap().iter() {
+                    for outgoing in &released {
+                        if client.tx.send(outgoing.clone()).is_err() {
+                            eprintln!("Failed to send event to {}", addr);
+                        }
+                    }
+                }
+            }
+
             let event = writer_file_watcher.lock().unwrap().try_get_event();
             if event.is_err() {
                 tokio::time::sleep(std::time::Duration::from_millis(10)).await;
@@ -25,71 +86,259 @@ pub(crate) async fn run(port: u16, root: &str) {
             let clients = writer_clients.lock().unwrap();
             eprintln!("Transmitting event to {} clients..", clients.len());
 
-            let event_data = writer_file_watcher.lock().unwrap().make_msg_data(&event.unwrap());
-            if event_data.is_none() {
+            let outgoing = writer_file_watcher.lock().unwrap().make_outgoing(&event.unwrap());
+            if outgoing.is_none() {
                 eprintln!("Failed to serialize event");
                 continue;
             }
 
-            let event_data = event_data.unwrap();
-            for (addr, tx) in clients.iter() {
-                if let Err(_) = tx.send(event_data.clone()) {
+            let outgoing = outgoing.unwrap();
+            for (addr, client) in clients.iter() {
+                if client.tx.send(outgoing.clone()).is_err() {
                     eprintln!("Failed to send event to {}", addr);
                 }
             }
         }
     });
 
+    // Disconnect everyone whose token was revoked. Clients whose access changed are disconnected
+    // too, reconnecting is what gets them a manifest that matches their new access.
+    let auth = Arc::new(Mutex::new(auth));
+    let reload_auth = auth.clone();
+    let reload_clients = clients.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(AUTH_RELOAD_INTERVAL).await;
+            let mut auth = reload_auth.lock().unwrap();
+            if !auth.reload() {
+                continue;
+            }
+
+            reload_clients.lock().unwrap().retain(|addr, client| {
+                let e = match auth.authenticate(client.identity.token.as_deref()) {
+                    Ok(identity) if identity.access == client.identity.access => return true,
+                    Ok(_) => "Its access changed".to_string(),
+                    Err(e) => e,
+                };
+                eprintln!("Disconnecting {} ({}): {}", addr, client.identity.name(), e);
+                client.reader.abort();
+                false
+            });
+        }
+    });
+
+    // Every listener accepts clients of its own, they all share the same tree
+    let listeners: Vec<_> = listeners
+        .into_iter()
+        .map(|listener| {
+            tokio::spawn(accept_clients(listener, file_watcher.clone(), clients.clone(), acceptor.clone(), auth.clone()))
+        })
+        .collect();
+    for listener in listeners {
+        if let Err(e) = listener.await {
+            eprintln!("Listener stopped: {:?}", e);
+        }
+    }
+}
+
+// Binds a listener to a single address. IPv6 addresses only take IPv6 connections, so
+// listening on both `0.0.0.0` and `::` doesn't fail on systems that map IPv4 into IPv6.
+fn listen(address: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, Some(Protocol::TCP))?;
+    if address.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+async fn accept_clients(
+    listener: TcpListener,
+    file_watcher: Arc<Mutex<FileWatcher>>,
+    clients: Clients,
+    acceptor: Option<TlsAcceptor>,
+    auth: Arc<Mutex<Auth>>,
+) {
     loop {
-        let (mut stream, addr) = listener.accept().await.unwrap();
-        let addr = addr.to_string();
-        let addr_read = addr.clone();
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                eprintln!("Failed to accept a connection: {}", e);
+                tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
         eprintln!("Client connected: {}", addr);
 
-        // Send initial sync
-        let files = file_watcher.lock().unwrap().get_relative_files();
-        let sync_event = MessageType::Sync { files };
-        write_msg(&mut stream, &sync_event).await.unwrap();
+        // Every client gets its own task, so one that's slow to authenticate doesn't hold up the rest
+        tokio::spawn(serve_client(
+            stream,
+            addr.to_string(),
+            file_watcher.clone(),
+            clients.clone(),
+            acceptor.clone(),
+            auth.clone(),
+        ));
+    }
+}
 
-        let (reader, mut writer) = stream.into_split();
-        let (tx, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
+async fn serve_client(
+    stream: TcpStream,
+    addr: String,
+    file_watcher: Arc<Mutex<FileWatcher>>,
+    clients: Clients,
+    acceptor: Option<TlsAcceptor>,
+    auth: Arc<Mutex<Auth>>,
+) {
+    let addr_read = addr.clone();
 
-        clients.lock().unwrap().insert(addr.clone(), tx);
+    let stream = tls::accept(stream, acceptor.as_ref()).await;
+    if let Err(e) = stream {
+        eprintln!("Failed to secure connection with {}, disconnecting: {}", addr, e);
+        return;
+    }
+    let (mut reader, mut writer) = stream.unwrap();
+    let capabilities = file_watcher.lock().unwrap().capabilities();
+    let session = server_handshake(&mut reader, &mut writer, &capabilities).await;
+    if let Err(e) = session {
+        eprintln!("Handshake with {} failed, disconnecting: {}", addr, e);
+        return;
+    }
+    let session = session.unwrap();
+    eprintln!("Client {} speaks protocol v{} with capabilities {:?}", addr, session.version, session.capabilities);
 
-        // Writer task
-        let write_clients = clients.clone();
-        tokio::spawn(async move {
-            eprintln!("Client writer waiting for commands: {}", addr);
-            while let Some(data) = rx.recv().await {
-                eprintln!("Client writer received data, {} bytes", data.len());
-                if writer.write_all(&data).await.is_err() {
-                    eprintln!("Failed to write to client {}", addr);
+    // Nothing from the tree goes out before this
+    let (authority, direction) = {
+        let file_watcher = file_watcher.lock().unwrap();
+        (file_watcher.authority(), file_watcher.direction())
+    };
+    let identity = server_authenticate(&mut reader, &mut writer, &auth, authority, direction).await;
+    if let Err(e) = identity {
+        eprintln!("Client {} failed to authenticate, disconnecting: {}", addr, e);
+        return;
+    }
+    let (identity, direction) = identity.unwrap();
+    eprintln!("Client {} authenticated as {}, syncing {}", addr, identity.name(), direction);
+    let source = direction.source(authority);
+    let takes_changes = direction.sends(authority, Authority::Client);
+    let sends_changes = direction.sends(authority, Authority::Server);
+
+    // Send the manifest, the client will request whatever it's missing. If the client is
+    // the source, it's the other way around.
+    let peer = Arc::new(Mutex::new(PeerHashes::default()));
+    if source == Authority::Server {
+        let manifest = Outgoing::Message(file_watcher.lock().unwrap().get_manifest());
+        for manifest in identity.access.filter(manifest, &file_watcher) {
+            if let Err(e) = write_outgoing(&mut writer, &manifest, &session, &peer).await {
+                eprintln!("Failed to send manifest to {}: {:?}", addr, e);
+                return;
+            }
+        }
+    }
+    let (tx, mut rx) = mpsc::unbounded_channel::<Outgoing>();
+    let write_peer = peer.clone();
+
+    // Reader task
+    let file_watcher_reader = file_watcher.clone();
+    let forward_clients = clients.clone();
+    let client_tx = tx.clone();
+    let read_access = identity.access.clone();
+    let read_root = std::path::PathBuf::from(&file_watcher.lock().unwrap().root);
+    let reader_task = tokio::spawn(async move {
+        let mut reader = reader;
+        let mut transfers = PeerTransfers::new(file_watcher_reader.clone());
+        loop {
+            let msg = read_msg(&mut reader).await;
+            if let Err(e) = msg {
+                eprintln!("Failed to read message from {}: {:?}", addr_read, e);
+                if e.is_disconnected() {
                     break;
                 }
+                continue;
+            }
+
+            let msg = msg.unwrap();
+            let allowed = if !takes_changes && msg.is_change() {
+                Err(format!("Changes only go from the server to this client ({})", direction))
+            } else {
+                read_access.check(&msg, &read_root).and_then(|_| transfers.track(&msg))
+            };
+            if let Err(e) = allowed {
+                eprintln!("Rejecting message from {}: {}", addr_read, e);
+                if tx.send(Outgoing::Message(MessageType::Error { message: format!("Rejected: {}", e) })).is_err() {
+                    eprintln!("Failed to reply to {}", addr_read);
+                }
+                continue;
+            }
+
+            let (replies, forwards, refused) = {
+                let mut file_watcher = file_watcher_reader.lock().unwrap();
+                let replies = file_watcher.handle_message(&msg, source == Authority::Server, &mut peer.lock().unwrap());
+                (replies, file_watcher.take_forwards(), file_watcher.take_refused())
+            };
+            for reply in replies {
+                if tx.send(reply).is_err() {
+                    eprintln!("Failed to reply to {}", addr_read);
+                }
             }
-            eprintln!("Client writer closed: {}", addr);
-            write_clients.lock().unwrap().remove(&addr);
-        });
-
-        // Reader task
-        let file_watcher_reader = file_watcher.clone();
-        tokio::spawn(async move {
-            let mut reader = reader;
-            loop {
-                let msg = read_msg(&mut reader).await;
-                if let Err(e) = msg {
-                    eprintln!("Failed to read message from {}: {:?}", addr_read, e);
-                    if e.is_disconnected() {
-                        break;
+
+            // The writer hangs up once it's sent why
+            if refused {
+                eprintln!("Refused the tree of {}, disconnecting", addr_read);
+                forward_clients.lock().unwrap().remove(&addr_read);
+                break;
+            }
+
+            // Pass the change on to everyone else, the sender already has it
+            if !forwards.is_empty() {
+                let clients = forward_clients.lock().unwrap();
+                for (addr, client) in clients.iter().filter(|(addr, _)| **addr != addr_read) {
+                    for forward in &forwards {
+                        if client.tx.send(forward.clone()).is_err() {
+                            eprintln!("Failed to forward change to {}", addr);
+                        }
                     }
-                    continue;
                 }
+            }
+        }
+
+        eprintln!("Client reader closed: {}", addr_read);
+    });
+
+    let write_access = identity.access.clone();
+    let write_file_watcher = file_watcher.clone();
+    let client = Client { tx: client_tx, identity, reader: reader_task.abort_handle() };
+    clients.lock().unwrap().insert(addr.clone(), client);
 
-                let msg = msg.unwrap();
-                file_watcher_reader.lock().unwrap().handle_message(&msg, true);
+    // Writer task
+    let write_clients = clients.clone();
+    tokio::spawn(async move {
+        eprintln!("Client writer waiting for commands: {}", addr);
+        'write: while let Some(outgoing) = rx.recv().await {
+            // Our changes don't go to a client that only sends
+            if !sends_changes && is_change(&outgoing) {
+                continue;
+            }
+            for outgoing in write_access.filter(outgoing, &write_file_watcher) {
+                if let Err(e) = write_outgoing(&mut writer, &outgoing, &session, &write_peer).await {
+                    eprintln!("Failed to write to client {}: {:?}", addr, e);
+                    if e.is_disconnected() {
+                        break 'write;
+                    }
+                }
             }
+        }
+        eprintln!("Client writer closed: {}", addr);
+        write_clients.lock().unwrap().remove(&addr);
+    });
+}
 
-            eprintln!("Client reader closed: {}", addr_read);
-        });
+fn is_change(outgoing: &Outgoing) -> bool {
+    match outgoing {
+        Outgoing::File { .. } | Outgoing::Files { .. } | Outgoing::Delta { .. } => true,
+        Outgoing::Message(msg) => msg.is_change(),
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,96 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Component, Path};
+
+// What to do with symlinks inside the synced folder.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SymlinkMode {
+    // Sync whatever the link points to, as if it were a regular file or directory
+    Follow,
+    // Sync the link itself
+    #[default]
+    Preserve,
+    // Leave links out of the sync entirely
+    Skip,
+}
+
+pub(crate) fn is_symlink(path: &Path) -> bool {
+    path.symlink_metadata().is_ok_and(|m| m.file_type().is_symlink())
+}
+
+pub(crate) fn read_target(path: &Path) -> Option<String> {
+    let target = std::fs::read_link(path).ok()?;
+    target.to_str().map(|t| t.to_string())
+}
+
+// Whether a link at `link` pointing to `target` ends up inside of `root`. Only relative targets
+// are allowed, and they can't leave the root at any point, as the root will have a different
+// name on the other side.
+pub(crate) fn stays_inside(root: &Path, link: &Path, target: &str) -> bool {
+    let target = Path::new(target);
+    if target.is_absolute() {
+        return false;
+    }
+
+    let mut path = link.parent().unwrap_or(root).to_path_buf();
+    for component in target.components() {
+        match component {
+            Component::CurDir => {}
+            Component::Normal(part) => path.push(part),
+            Component::ParentDir => {
+                if !path.pop() {
+                    return false;
+                }
+            }
+            _ => return false,
+        }
+
+        if !path.starts_with(root) {
+            return false;
+        }
+    }
+    true
+}
+
+// Where a link at `link` pointing to `target` leads, both relative to the root. None if it
+// leaves the root on the way, see `stays_inside`.
+pub(crate) fn target_path(link: &str, target: &str) -> Option<String> {
+    let mut path: Vec<&str> = link.split('/').collect();
+    path.pop();
+    for component in Path::new(target).components() {
+        match component {
+            Component::CurDir => {}
+            Component::Normal(part) => path.push(part.to_str()?),
+            Component::ParentDir => {
+                path.pop()?;
+            }
+            _ => return None,
+        }
+    }
+    Some(path.join("/"))
+}
+
+// Whether a link resolves to something inside of `root`, for links we're about to follow.
+pub(crate) fn resolves_inside(root: &Path, link: &Path) -> bool {
+    match (root.canonicalize(), link.canonicalize()) {
+        (Ok(root), Ok(resolved)) => resolved.starts_with(root),
+        _ => false,
+    }
+}
+
+// Creates (or replaces) the link at `path`.
+pub(crate) fn create(path: &Path, target: &str) -> std::io::Result<()> {
+    if is_symlink(path) || path.is_file() {
+        std::fs::remove_file(path)?;
+    }
+
+    #[cfg(unix)]
+    return std::os::unix::fs::symlink(target, path);
+
+    #[cfg(windows)]
+    return if path.parent().unwrap_or(path).join(target).is_dir() {
+        std::os::windows::fs::symlink_dir(target, path)
+    } else {
+        std::os::windows::fs::symlink_file(target, path)
+    };
+}
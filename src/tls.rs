@@ -0,0 +1,187 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use tokio_rustls::rustls::crypto::{CryptoProvider, ring, verify_tls12_signature, verify_tls13_signature};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{self, DigitallySignedStruct, SignatureScheme};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::protocol::HANDSHAKE_TIMEOUT;
+
+// Either half of a connection, whether it's plain TCP or TLS.
+pub(crate) type Reader = Box<dyn AsyncRead + Unpin + Send>;
+pub(crate) type Writer = Box<dyn AsyncWrite + Unpin + Send>;
+
+// TLS settings for the server. Both are paths to PEM files, see `remote-fs init server --tls`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct ServerTls {
+    pub(crate) certificate: String,
+    pub(crate) key: String,
+}
+
+// TLS settings for the client. The server's certificate is self-signed, so instead of checking
+// it against a CA, we only accept the one with this SHA-256 fingerprint.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct ClientTls {
+    pub(crate) fingerprint: String,
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+pub(crate) fn acceptor(config: &ServerTls) -> Result<TlsAcceptor, String> {
+    let certificate = std::fs::read(&config.certificate)
+        .map_err(|e| format!("Failed to read certificate '{}': {}", config.certificate, e))?;
+    let certificates = CertificateDer::pem_slice_iter(&certificate)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Failed to parse certificate '{}': {:?}", config.certificate, e))?;
+
+    let key = std::fs::read(&config.key).map_err(|e| format!("Failed to read key '{}': {}", config.key, e))?;
+    let key = PrivateKeyDer::from_pem_slice(&key).map_err(|e| format!("Failed to parse key '{}': {:?}", config.key, e))?;
+
+    let config = rustls::ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(certificates, key))
+        .map_err(|e| format!("Invalid TLS configuration: {}", e))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+pub(crate) fn connector(config: &ClientTls) -> Result<TlsConnector, String> {
+    let fingerprint = normalize_fingerprint(&config.fingerprint);
+    if fingerprint.len() != 64 || !fingerprint.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("'{}' is not a SHA-256 fingerprint", config.fingerprint));
+    }
+
+    let verifier = PinnedCertificate { fingerprint, provider: provider() };
+    let config = rustls::ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| format!("Invalid TLS configuration: {}", e))?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+// Wraps a freshly accepted connection in TLS, if the server is configured to use it.
+pub(crate) async fn accept(stream: TcpStream, acceptor: Option<&TlsAcceptor>) -> Result<(Reader, Writer), String> {
+    let Some(acceptor) = acceptor else {
+        let (reader, writer) = stream.into_split();
+        return Ok((Box::new(reader), Box::new(writer)));
+    };
+
+    let stream = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+        .await
+        .map_err(|_| "Timed out waiting for TLS handshake".to_string())?
+        .map_err(|e| format!("TLS handshake failed: {}", e))?;
+    let (reader, writer) = tokio::io::split(stream);
+    Ok((Box::new(reader), Box::new(writer)))
+}
+
+// Wraps a connection to the server in TLS, if the client is configured to use it.
+pub(crate) async fn connect(stream: TcpStream, host: &str, connector: Option<&TlsConnector>) -> Result<(Reader, Writer), String> {
+    let Some(connector) = connector else {
+        let (reader, writer) = stream.into_split();
+        return Ok((Box::new(reader), Box::new(writer)));
+    };
+
+    // Only used for SNI, the certificate is checked against the pinned fingerprint instead
+    let server_name = ServerName::try_from(host.to_string()).map_err(|e| format!("Invalid server name '{}': {}", host, e))?;
+    let stream = tokio::time::timeout(HANDSHAKE_TIMEOUT, connector.connect(server_name, stream))
+        .await
+        .map_err(|_| "Timed out waiting for TLS handshake".to_string())?
+        .map_err(|e| format!("TLS handshake failed: {}", e))?;
+    let (reader, writer) = tokio::io::split(stream);
+    Ok((Box::new(reader), Box::new(writer)))
+}
+
+// Generates a self-signed certificate and its key, and returns the certificate's fingerprint.
+pub(crate) fn generate_certificate(certificate_path: &str, key_path: &str) -> Result<String, String> {
+    let names = vec!["remote-fs".to_string(), "localhost".to_string()];
+    let certified = rcgen::generate_simple_self_signed(names).map_err(|e| format!("Failed to generate certificate: {}", e))?;
+
+    std::fs::write(certificate_path, certified.cert.pem())
+        .map_err(|e| format!("Failed to write certificate '{}': {}", certificate_path, e))?;
+    write_private(key_path, certified.key_pair.serialize_pem())
+        .map_err(|e| format!("Failed to write key '{}': {}", key_path, e))?;
+    Ok(fingerprint(certified.cert.der()))
+}
+
+// Nobody but us should be able to read the key
+fn write_private(path: &str, contents: String) -> std::io::Result<()> {
+    let mut options = std::fs::File::options();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options.open(path)?;
+    std::io::Write::write_all(&mut file, contents.as_bytes())
+}
+
+// The SHA-256 of a certificate, as colon separated hex like most tools print it.
+pub(crate) fn fingerprint(certificate: &[u8]) -> String {
+    Sha256::digest(certificate)
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+// Fingerprints are compared without separators or case, so they can be pasted from anywhere.
+fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(|c| !matches!(c, ':' | ' ' | '-'))
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+#[derive(Debug)]
+struct PinnedCertificate {
+    fingerprint: String,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint = fingerprint(end_entity);
+        if normalize_fingerprint(&fingerprint) != self.fingerprint {
+            eprintln!("Server certificate has fingerprint {}, which is not the one we pinned", fingerprint);
+            return Err(rustls::Error::InvalidCertificate(rustls::CertificateError::ApplicationVerificationFailure));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
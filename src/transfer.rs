@@ -0,0 +1,289 @@
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::file_watcher::{FNV1A64_INIT, FileWatcher, fnv1a64, fnv1a64_update};
+use crate::conflict::PeerHashes;
+use crate::metadata::FileMetadata;
+use crate::message_handler::{MessageError, MessageType, write_msg};
+
+// Files bigger than this are streamed in chunks, instead of being read into memory and sent
+// as a single message.
+pub(crate) const CHUNKED_THRESHOLD: u64 = 4 * 1024 * 1024;
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+// How many files a single peer can be sending us at once. Ours send them one at a time.
+pub(crate) const MAX_TRANSFERS: usize = 8;
+
+static NEXT_TRANSFER_ID: AtomicU64 = AtomicU64::new(0);
+
+// Transfer ids only have to be unique on the receiving end, but a server receives from
+// many clients at once, so a plain counter isn't enough.
+pub(crate) fn next_transfer_id() -> u64 {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64;
+    let count = NEXT_TRANSFER_ID.fetch_add(1, Ordering::Relaxed);
+    fnv1a64(&[now.to_be_bytes(), count.to_be_bytes()].concat())
+}
+
+// Streams a file from disk to the writer, one chunk at a time.
+pub(crate) async fn send_file<W>(
+    writer: &mut W,
+    path: &str,
+    abs_path: &str,
+    metadata: Option<FileMetadata>,
+    peer: &Mutex<PeerHashes>,
+) -> Result<(), MessageError>
+where
+    W: AsyncWriteExt + Unpin,
+{
+    let file = tokio::fs::File::open(abs_path).await;
+    if let Err(e) = file {
+        eprintln!("Failed to open {} for transfer: {:?}", abs_path, e);
+        return Err(MessageError::file_error("Failed to open file"));
+    }
+
+    let mut file = file.unwrap();
+    let size = file.metadata().await.map(|m| m.len()).unwrap_or(0);
+    let id = next_transfer_id();
+    let parent_hash = Some(peer.lock().unwrap().parent_of(path));
+    write_msg(writer, &MessageType::TransferBegin { id, path: path.to_string(), size, metadata, parent_hash }).await?;
+
+    let mut offset: u64 = 0;
+    let mut hash = FNV1A64_INIT;
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    loop {
+        let read = file.read(&mut buf).await;
+        if let Err(e) = read {
+            eprintln!("Failed to read {} during transfer: {:?}", abs_path, e);
+            // Let the other side clean up its temp file
+            write_msg(writer, &MessageType::TransferEnd { id, hash: 0 }).await?;
+            return Err(MessageError::file_error("Failed to read file"));
+        }
+
+        let read = read.unwrap();
+        if read == 0 {
+            break;
+        }
+
+        let data = buf[..read].to_vec();
+        hash = fnv1a64_update(hash, &data);
+        write_msg(writer, &MessageType::TransferChunk { id, offset, data }).await?;
+        offset += read as u64;
+    }
+
+    write_msg(writer, &MessageType::TransferEnd { id, hash }).await?;
+    peer.lock().unwrap().set(path, hash);
+    eprintln!("Streamed {} ({} bytes)", path, offset);
+    Ok(())
+}
+
+// A file being received in chunks. Data goes into a hidden temp file next to the
+// destination, which replaces the destination once the transfer is complete.
+pub(crate) struct IncomingTransfer {
+    pub path: String,
+    pub metadata: Option<FileMetadata>,
+    pub parent_hash: Option<u64>,
+    temp_path: PathBuf,
+    file: std::fs::File,
+    size: u64,
+    written: u64,
+    hash: u64,
+}
+
+impl IncomingTransfer {
+    pub fn begin(
+        path: &str,
+        abs_path: &str,
+        id: u64,
+        size: u64,
+        metadata: Option<FileMetadata>,
+        parent_hash: Option<u64>,
+    ) -> std::io::Result<Self> {
+        let abs_path = Path::new(abs_path);
+        let parent = abs_path.parent().unwrap_or(Path::new("/"));
+        std::fs::create_dir_all(parent)?;
+
+        let file_name = abs_path.file_name().unwrap_or_default().to_string_lossy();
+        let temp_path = parent.join(format!(".{}.{:016x}.remote-fs-tmp", file_name, id));
+        let file = std::fs::File::create(&temp_path)?;
+
+        Ok(IncomingTransfer {
+            path: path.to_string(),
+            metadata,
+            parent_hash,
+            temp_path,
+            file,
+            size,
+            written: 0,
+            hash: FNV1A64_INIT,
+        })
+    }
+
+    pub fn write_chunk(&mut self, offset: u64, data: &[u8]) -> Result<(), String> {
+        if offset != self.written {
+            return Err(format!("Expected chunk at offset {}, got {}", self.written, offset));
+        }
+        // Nothing past what was announced, or a peer could fill up the disk before `finish` notices
+        if self.written + data.len() as u64 > self.size {
+            return Err(format!("Chunk goes past the announced size of {} bytes", self.size));
+        }
+
+        self.file
+            .write_all(data)
+            .map_err(|e| format!("Failed to write chunk: {:?}", e))?;
+        self.written += data.len() as u64;
+        self.hash = fnv1a64_update(self.hash, data);
+        Ok(())
+    }
+
+    // Moves the temp file into place, returns the hash of the new contents.
+    pub fn finish(self, abs_path: &str, hash: u64) -> Result<u64, String> {
+        if self.hash != hash || self.written != self.size {
+            let error = if self.written != self.size {
+                format!("Transfer of {} is incomplete ({} of {} bytes received)", self.path, self.written, self.size)
+            } else {
+                format!("Received {} with the wrong contents", self.path)
+            };
+            self.abort();
+            return Err(error);
+        }
+
+        if let Err(e) = self.file.sync_all() {
+            eprintln!("Failed to flush {}: {:?}", self.temp_path.display(), e);
+        }
+
+        if let Err(e) = std::fs::rename(&self.temp_path, abs_path) {
+            let error = format!("Failed to move {} into place: {:?}", self.path, e);
+            self.abort();
+            return Err(error);
+        }
+
+        Ok(self.hash)
+    }
+
+    pub fn abort(self) {
+        drop(self.file);
+        if let Err(e) = std::fs::remove_file(&self.temp_path) {
+            eprintln!("Failed to remove {}: {:?}", self.temp_path.display(), e);
+        }
+    }
+}
+
+// The transfers the other side of a connection has under way. Whatever it didn't finish is
+// aborted once its reader stops, however that happens, so its temp files don't stay behind.
+pub(crate) struct PeerTransfers {
+    ids: HashSet<u64>,
+    file_watcher: Arc<Mutex<FileWatcher>>,
+}
+
+impl PeerTransfers {
+    pub fn new(file_watcher: Arc<Mutex<FileWatcher>>) -> Self {
+        PeerTransfers { ids: HashSet::new(), file_watcher }
+    }
+
+    // Chunks only go to the peer's own transfers, and it can only have so many at once.
+    pub fn track(&mut self, msg: &MessageType) -> Result<(), String> {
+        match msg {
+            MessageType::TransferBegin { id, .. } => {
+                if self.ids.len() >= MAX_TRANSFERS {
+                    return Err(format!("Already receiving {} files", MAX_TRANSFERS));
+                }
+                if !self.ids.insert(*id) {
+                    return Err(format!("Transfer {:016x} is already under way", id));
+                }
+            }
+            MessageType::TransferChunk { id, .. } if !self.ids.contains(id) => {
+                return Err(format!("Transfer {:016x} isn't under way", id));
+            }
+            MessageType::TransferEnd { id, .. } if !self.ids.remove(id) => {
+                return Err(format!("Transfer {:016x} isn't under way", id));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PeerTransfers {
+    fn drop(&mut self) {
+        if let Ok(mut file_watcher) = self.file_watcher.lock() {
+            file_watcher.abort_transfers(&self.ids);
+        }
+    }
+}
+
+// A delta is applied straight into the temp file, see `apply_delta`.
+impl Write for IncomingTransfer {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let written = self.file.write(data)?;
+        self.written += written as u64;
+        self.hash = fnv1a64_update(self.hash, &data[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn begin(name: &str, size: u64) -> (PathBuf, IncomingTransfer) {
+        let dir = std::env::temp_dir().join(format!("remote-fs-transfer-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let abs_path = dir.join("file");
+        let transfer = IncomingTransfer::begin("file", &abs_path.to_string_lossy(), 1, size, None, None).unwrap();
+        (dir, transfer)
+    }
+
+    #[test]
+    fn receives_a_file_in_chunks() {
+        let (dir, mut transfer) = begin("chunks", 6);
+        transfer.write_chunk(0, b"abc").unwrap();
+        transfer.write_chunk(3, b"def").unwrap();
+
+        let abs_path = dir.join("file");
+        assert_eq!(transfer.finish(&abs_path.to_string_lossy(), fnv1a64(b"abcdef")).unwrap(), fnv1a64(b"abcdef"));
+        assert_eq!(std::fs::read(&abs_path).unwrap(), b"abcdef");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_chunks_out_of_order() {
+        let (dir, mut transfer) = begin("order", 6);
+        assert!(transfer.write_chunk(3, b"def").is_err());
+        transfer.abort();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_chunks_past_the_announced_size() {
+        let (dir, mut transfer) = begin("size", 4);
+        transfer.write_chunk(0, b"abc").unwrap();
+        assert!(transfer.write_chunk(3, b"def").is_err());
+        assert_eq!(transfer.written, 3);
+
+        transfer.abort();
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_an_incomplete_file() {
+        let (dir, mut transfer) = begin("incomplete", 6);
+        transfer.write_chunk(0, b"abc").unwrap();
+
+        let abs_path = dir.join("file");
+        assert!(transfer.finish(&abs_path.to_string_lossy(), fnv1a64(b"abc")).is_err());
+        assert!(!abs_path.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
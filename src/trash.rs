@@ -0,0 +1,224 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::journal::STATE_DIR;
+
+// Where the trash is unless it's configured, inside of the state folder
+const DEFAULT_DIR: &str = "trash";
+
+// Points `remote-fs restore` to a trash that's somewhere else, written on startup
+const DIR_FILE: &str = "trash-dir";
+
+// Keeps the previous version of every file a change from the other side overwrites or
+// deletes, so a sync mistake can be undone with `remote-fs restore <path>`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct TrashOptions {
+    // Defaults to `.remote-fs/trash` in the synced folder. Anywhere else in the folder it
+    // has to be hidden, or it would be synced itself.
+    #[serde(default)]
+    pub(crate) dir: Option<String>,
+
+    // Per file, the oldest ones go first. Without a limit, every version is kept.
+    #[serde(default = "default_max_versions")]
+    pub(crate) max_versions: Option<usize>,
+
+    #[serde(default = "default_max_age_days")]
+    pub(crate) max_age_days: Option<u64>,
+}
+
+fn default_max_versions() -> Option<usize> {
+    Some(10)
+}
+
+fn default_max_age_days() -> Option<u64> {
+    Some(30)
+}
+
+// Versions are kept under the same relative path as the file, as `<name>@<milliseconds since the epoch>`.
+pub(crate) struct Trash {
+    dir: PathBuf,
+    options: TrashOptions,
+}
+
+impl Trash {
+    pub(crate) fn new(root: &Path, options: TrashOptions) -> Result<Self, String> {
+        let state_dir = root.join(STATE_DIR);
+        let dir = match &options.dir {
+            Some(dir) => std::path::absolute(dir).map_err(|e| format!("Invalid trash directory '{}': {}", dir, e))?,
+            None => state_dir.join(DEFAULT_DIR),
+        };
+
+        if let Ok(inside) = dir.strip_prefix(root)
+            && !inside.components().any(|c| c.as_os_str().to_string_lossy().starts_with('.'))
+        {
+            return Err(format!("The trash directory '{}' would be synced, it has to be hidden", dir.display()));
+        }
+
+        std::fs::create_dir_all(&dir).map_err(|e| format!("Failed to create trash directory '{}': {}", dir.display(), e))?;
+        std::fs::create_dir_all(&state_dir).map_err(|e| format!("Failed to create '{}': {}", state_dir.display(), e))?;
+        let dir_file = state_dir.join(DIR_FILE);
+        let result = match options.dir {
+            Some(_) => std::fs::write(&dir_file, dir.to_string_lossy().as_bytes()),
+            None if dir_file.exists() => std::fs::remove_file(&dir_file),
+            None => Ok(()),
+        };
+        result.map_err(|e| format!("Failed to update '{}': {}", dir_file.display(), e))?;
+
+        let trash = Trash { dir, options };
+        trash.expire_all();
+        Ok(trash)
+    }
+
+    // Copies the file at `abs_path`, which is `path` relative to the root, into the trash.
+    // Empty files aren't worth a version, and writing a file often empties it first.
+    pub(crate) fn keep(&self, path: &str, abs_path: &Path) {
+        if !std::fs::metadata(abs_path).is_ok_and(|m| m.is_file() && m.len() > 0) {
+            return;
+        }
+
+        let target = self.dir.join(path);
+        let parent = target.parent().unwrap();
+        let name = target.file_name().unwrap().to_string_lossy().to_string();
+        if let Err(e) = std::fs::create_dir_all(parent) {
+            eprintln!("Failed to create {}, not keeping the previous version of {}: {:?}", parent.display(), path, e);
+            return;
+        }
+
+        // Two versions within the same millisecond are rare, but shouldn't overwrite each other
+        let mut at = now();
+        while parent.join(version_name(&name, at)).exists() {
+            at += 1;
+        }
+        if let Err(e) = std::fs::copy(abs_path, parent.join(version_name(&name, at))) {
+            eprintln!("Failed to keep the previous version of {}: {:?}", path, e);
+            return;
+        }
+
+        self.expire(parent, &name);
+    }
+
+    // Drops the versions of one file that are past the limits.
+    fn expire(&self, dir: &Path, name: &str) {
+        let mut versions = versions_in(dir, name);
+        let max_age = self.options.max_age_days.map(|days| days * 24 * 60 * 60 * 1000);
+        for (i, (at, path)) in versions.drain(..).enumerate() {
+            let too_many = self.options.max_versions.is_some_and(|max| i >= max);
+            let too_old = max_age.is_some_and(|max| now().saturating_sub(at) > max);
+            if (too_many || too_old)
+                && let Err(e) = std::fs::remove_file(&path)
+            {
+                eprintln!("Failed to remove {} from the trash: {:?}", path.display(), e);
+            }
+        }
+    }
+
+    // Only the age limit can have been passed while we weren't running.
+    fn expire_all(&self) {
+        if self.options.max_age_days.is_none() {
+            return;
+        }
+
+        let mut dirs = vec![self.dir.clone()];
+        while let Some(dir) = dirs.pop() {
+            let Ok(entries) = std::fs::read_dir(&dir) else {
+                continue;
+            };
+            let mut names = Vec::new();
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else if let Some((name, _)) = parse_version(&entry.file_name().to_string_lossy()) {
+                    names.push(name);
+                }
+            }
+
+            names.sort();
+            names.dedup();
+            for name in names {
+                self.expire(&dir, &name);
+            }
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+fn version_name(name: &str, at: u64) -> String {
+    format!("{}@{}", name, at)
+}
+
+fn parse_version(file_name: &str) -> Option<(String, u64)> {
+    let (name, at) = file_name.rsplit_once('@')?;
+    Some((name.to_string(), at.parse().ok()?))
+}
+
+// The versions of `name` in `dir`, newest first.
+fn versions_in(dir: &Path, name: &str) -> Vec<(u64, PathBuf)> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return vec![];
+    };
+    let mut versions: Vec<(u64, PathBuf)> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let (version_of, at) = parse_version(&entry.file_name().to_string_lossy())?;
+            (version_of == name).then(|| (at, entry.path()))
+        })
+        .collect();
+    versions.sort_by_key(|(at, _)| std::cmp::Reverse(*at));
+    versions
+}
+
+// Puts a version of `path` from the trash back, the newest one unless `version` says otherwise.
+// Versions are numbered from 1, the newest. The file's current contents are kept in the trash
+// first, so a restore can be undone as well. A running instance syncs the result like any other
+// change made here.
+pub(crate) fn restore(path: &str, version: Option<usize>) -> Result<(), String> {
+    let abs_path = std::path::absolute(path).map_err(|e| format!("Invalid path '{}': {}", path, e))?;
+    let root = abs_path
+        .ancestors()
+        .skip(1)
+        .find(|dir| dir.join(STATE_DIR).is_dir())
+        .ok_or(format!("'{}' isn't in a synced folder that keeps a trash", path))?;
+    let dir = match std::fs::read_to_string(root.join(STATE_DIR).join(DIR_FILE)) {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => root.join(STATE_DIR).join(DEFAULT_DIR),
+    };
+
+    let relative_path = abs_path.strip_prefix(root).unwrap();
+    let target = dir.join(relative_path);
+    let name = target.file_name().unwrap().to_string_lossy().to_string();
+    let versions = versions_in(target.parent().unwrap(), &name);
+    if versions.is_empty() {
+        return Err(format!("There are no previous versions of '{}'", relative_path.display()));
+    }
+
+    let version = version.unwrap_or(1);
+    for (i, (at, version_path)) in versions.iter().enumerate() {
+        let size = std::fs::metadata(version_path).map(|m| m.len()).unwrap_or(0);
+        let marker = if i + 1 == version { '*' } else { ' ' };
+        eprintln!("{} {:>3}  {:>10} ago  {} bytes", marker, i + 1, format_age(now().saturating_sub(*at)), size);
+    }
+    let (_, version_path) = versions
+        .get(version.wrapping_sub(1))
+        .ok_or(format!("There's no version {}, only {}", version, versions.len()))?;
+
+    let trash = Trash { dir, options: TrashOptions { dir: None, max_versions: None, max_age_days: None } };
+    trash.keep(&relative_path.to_string_lossy(), &abs_path);
+    std::fs::create_dir_all(abs_path.parent().unwrap()).map_err(|e| format!("Failed to create '{}': {}", abs_path.parent().unwrap().display(), e))?;
+    std::fs::copy(version_path, &abs_path).map_err(|e| format!("Failed to restore '{}': {}", path, e))?;
+    Ok(())
+}
+
+fn format_age(millis: u64) -> String {
+    let age = Duration::from_millis(millis).as_secs();
+    match age {
+        0..60 => format!("{}s", age),
+        60..3600 => format!("{}m", age / 60),
+        3600..86400 => format!("{}h", age / 3600),
+        _ => format!("{}d", age / 86400),
+    }
+}